@@ -0,0 +1,599 @@
+//! Conversion of ADC counts to microvolts.
+//!
+//! Each channel has its own gain and offset: `microvolts = (counts - offset) * gain`. Coefficients
+//! are looked up by the serial number of the monitor, first among the ones measured on site, see
+//! `CalibrationStore::store`, then in the factory table. Monitors found in neither are converted
+//! with nominal coefficients computed from the front end gain in their configuration.
+//!
+//! `self_check` measures the coefficients of a monitor with the 1 mV calibration pulse of its
+//! front end.
+use crate::client::{ClientError, HolterClient};
+use crate::samples::{PacketLayout, SampleBlock, SampleUnit, StatusFlags};
+use crate::stream::{SampleSubscription, StreamItem};
+use crate::usb::USBDevices;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Reference voltage of the ADC, full scale is plus or minus this
+pub const ADC_REFERENCE_MICROVOLTS: f32 = 2_400_000.0;
+/// Height of the calibration pulse
+pub const CALIBRATION_PULSE_MICROVOLTS: f32 = 1000.0;
+/// The calibration pulse is a 1 Hz square wave
+pub const CALIBRATION_PULSE_PERIOD_MS: f32 = 1000.0;
+/// Largest relative deviation of the measured pulse height that passes the check
+pub const AMPLITUDE_TOLERANCE: f32 = 0.05;
+/// Largest relative deviation of the measured pulse period that passes the check
+pub const PERIOD_TOLERANCE: f32 = 0.02;
+// A channel that far off is broken rather than out of calibration, it is not corrected
+const MAX_GAIN_CORRECTION: f32 = 0.5;
+// Pulse periods captured per check
+const CAPTURE_PERIODS: f32 = 4.0;
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum CalibrationError {
+    #[error(transparent)]
+    Client(#[from] ClientError),
+    #[error("no device {0}")]
+    NotFound(String),
+    #[error("device {0} has no serial number to keep its calibration under")]
+    NoSerial(String),
+    #[error("no live stream from device {0}")]
+    NoStream(String),
+    #[error("no calibration pulse within {0:?}")]
+    Timeout(Duration),
+    #[error("no calibration pulse on channel {0}")]
+    NoPulse(usize),
+    #[error("failed to save the calibration: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelCalibration {
+    pub gain_microvolts_per_count: f32,
+    pub offset_counts: f32,
+}
+
+/// Where the coefficients of a calibration come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CalibrationSource {
+    /// Computed from the configured gain and the ADC resolution
+    Nominal,
+    Factory,
+    /// Measured with the calibration signal of the monitor
+    Measured,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Calibration {
+    pub source: CalibrationSource,
+    pub channels: Vec<ChannelCalibration>,
+}
+
+impl Calibration {
+    /// Coefficients for an ideal front end with the given gain
+    pub fn nominal(layout: PacketLayout, gain: u8) -> Self {
+        let full_scale = (1u32 << (layout.format.bytes() * 8 - 1)) as f32;
+        let channel = ChannelCalibration {
+            gain_microvolts_per_count: ADC_REFERENCE_MICROVOLTS / full_scale / f32::from(gain.max(1)),
+            offset_counts: 0.0,
+        };
+        Calibration {
+            source: CalibrationSource::Nominal,
+            channels: vec![channel; layout.channels],
+        }
+    }
+
+    /// Converts a block of ADC counts to microvolts. Blocks already converted, or with another
+    /// number of channels, are left alone and false is returned.
+    pub fn apply(&self, block: &mut SampleBlock) -> bool {
+        if block.unit != SampleUnit::AdcCounts || block.channels != self.channels.len() {
+            return false;
+        }
+        for (i, sample) in block.samples.iter_mut().enumerate() {
+            let channel = &self.channels[i % block.channels];
+            *sample = (*sample - channel.offset_counts) * channel.gain_microvolts_per_count;
+        }
+        block.unit = SampleUnit::Microvolts;
+        true
+    }
+}
+
+// Calibration applied to the live stream of a device. It is shared between the device loop and
+// the registry so that a new calibration takes effect on the next block.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ActiveCalibration(Arc<Mutex<Option<Calibration>>>);
+
+impl ActiveCalibration {
+    pub(crate) fn get(&self) -> Option<Calibration> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub(crate) fn set(&self, calibration: Option<Calibration>) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = calibration;
+    }
+
+    pub(crate) fn apply(&self, block: &mut SampleBlock) -> bool {
+        match &*self.0.lock().unwrap_or_else(PoisonError::into_inner) {
+            Some(calibration) => calibration.apply(block),
+            None => false,
+        }
+    }
+}
+
+/// Calibrations keyed by serial number
+#[derive(Debug, Clone, Default)]
+pub struct CalibrationStore {
+    factory: HashMap<String, Calibration>,
+    measured: HashMap<String, Calibration>,
+    // File the measured calibrations are saved to
+    path: Option<PathBuf>,
+}
+
+impl CalibrationStore {
+    /// Loads the factory table and the measured calibrations, both JSON objects keyed by serial
+    /// number. Measured calibrations are saved back to `measured`, which need not exist yet.
+    pub fn load(factory: Option<&Path>, measured: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut store = CalibrationStore {
+            path: Some(measured.to_path_buf()),
+            ..Default::default()
+        };
+        if let Some(factory) = factory {
+            store.factory = read_table(factory, CalibrationSource::Factory)?;
+        }
+        if measured.exists() {
+            store.measured = read_table(measured, CalibrationSource::Measured)?;
+        }
+        Ok(store)
+    }
+
+    pub fn lookup(&self, serial: &str) -> Option<&Calibration> {
+        self.measured.get(serial).or_else(|| self.factory.get(serial))
+    }
+
+    /// Keeps a measured calibration. Returns it together with all measured calibrations to save,
+    /// unless the store has no file.
+    pub fn store(
+        &mut self,
+        serial: &str,
+        channels: Vec<ChannelCalibration>,
+    ) -> (Calibration, Option<MeasuredFile>) {
+        let calibration = Calibration {
+            source: CalibrationSource::Measured,
+            channels,
+        };
+        self.measured.insert(serial.to_string(), calibration.clone());
+        let file = self.path.as_ref().map(|path| MeasuredFile {
+            path: path.clone(),
+            measured: self.measured.clone(),
+        });
+        (calibration, file)
+    }
+}
+
+/// The measured calibrations of a `CalibrationStore` as they are to be saved
+#[derive(Debug, Clone)]
+pub struct MeasuredFile {
+    path: PathBuf,
+    measured: HashMap<String, Calibration>,
+}
+
+impl MeasuredFile {
+    /// Writes the file, which blocks. The new contents go to a temporary file next to it that is
+    /// renamed over the old one, so a crash leaves either the old or the new calibrations.
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let temp = self.path.with_file_name(name);
+        let result = write_synced(&temp, &serde_json::to_vec_pretty(&self.measured)?)
+            .and_then(|()| std::fs::rename(&temp, &self.path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+        result
+    }
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+// The source is implied by the file, so it may be left out of the entries
+fn read_table(
+    path: &Path,
+    source: CalibrationSource,
+) -> Result<HashMap<String, Calibration>, Box<dyn std::error::Error>> {
+    #[derive(Deserialize)]
+    struct Entry {
+        channels: Vec<ChannelCalibration>,
+    }
+    let entries: HashMap<String, Entry> = serde_json::from_slice(&std::fs::read(path)?)?;
+    Ok(entries
+        .into_iter()
+        .map(|(serial, entry)| (serial, Calibration { source, channels: entry.channels }))
+        .collect())
+}
+
+/// The calibration pulse as measured on one channel
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelCheck {
+    pub channel: usize,
+    /// Height of the pulse with the calibration in use
+    pub amplitude_microvolts: f32,
+    pub period_ms: f32,
+    /// Whether amplitude and period are within `AMPLITUDE_TOLERANCE` and `PERIOD_TOLERANCE`
+    pub in_tolerance: bool,
+    /// Coefficients that make the pulse exactly `CALIBRATION_PULSE_MICROVOLTS` high
+    pub corrected: ChannelCalibration,
+}
+
+/// Outcome of `self_check`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelfCheck {
+    /// All channels are within tolerance
+    pub passed: bool,
+    pub channels: Vec<ChannelCheck>,
+    /// Calibration the pulse was measured with
+    pub previous: Calibration,
+    /// The corrected calibration, now stored for the device. None when a channel is too far off
+    /// to be corrected.
+    pub stored: Option<Calibration>,
+}
+
+/// Checks the monitor against its calibration pulse. The inputs are switched to the pulse for a
+/// few periods, its height and period are measured on every channel and the corrected
+/// coefficients are stored for the serial number of the monitor.
+pub async fn self_check(
+    usb_devices: &USBDevices,
+    client: &mut HolterClient,
+    id: &str,
+) -> Result<SelfCheck, CalibrationError> {
+    let mut samples = usb_devices
+        .subscribe_samples(id)
+        .await
+        .ok_or_else(|| CalibrationError::NotFound(id.to_string()))?;
+    // Reading the config also gives the live stream the gain of its nominal calibration
+    let config = client.config().await?;
+    let previous = usb_devices
+        .calibration(id)
+        .await
+        .ok_or_else(|| CalibrationError::NoStream(id.to_string()))?;
+
+    client.set_calibration_mode(true).await?;
+    // Back to the electrodes whatever the outcome, also when the check is dropped halfway
+    let mode = CalibrationMode(Some(client.share()));
+    let period_frames = f32::from(config.sample_rate_hz) * CALIBRATION_PULSE_PERIOD_MS / 1000.0;
+    let frames = (period_frames * CAPTURE_PERIODS) as usize;
+    let captured = capture_pulse(&mut samples, frames, id).await;
+    let restored = mode.restore().await;
+    let blocks = captured?;
+    restored?;
+
+    let channels = analyze_pulse(&blocks, &previous, config.sample_rate_hz)?;
+    let correctable = channels.iter().all(|check| {
+        let correction = check.amplitude_microvolts / CALIBRATION_PULSE_MICROVOLTS;
+        (correction - 1.0).abs() <= MAX_GAIN_CORRECTION
+    });
+    let stored = if correctable {
+        let corrected = channels.iter().map(|check| check.corrected).collect();
+        Some(usb_devices.store_calibration(id, corrected).await?)
+    } else {
+        warn!("Calibration of {} not corrected, a channel is too far off", id);
+        None
+    };
+    Ok(SelfCheck {
+        passed: channels.iter().all(|check| check.in_tolerance),
+        channels,
+        previous,
+        stored,
+    })
+}
+
+// Switches the calibration mode off again. Unless `restore` is awaited, that happens in the
+// background once it is dropped.
+struct CalibrationMode(Option<HolterClient>);
+
+impl CalibrationMode {
+    async fn restore(mut self) -> Result<(), ClientError> {
+        match self.0.take() {
+            Some(mut client) => client.set_calibration_mode(false).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for CalibrationMode {
+    fn drop(&mut self) {
+        if let Some(mut client) = self.0.take() {
+            tokio::spawn(async move {
+                if let Err(e) = client.set_calibration_mode(false).await {
+                    error!("Failed to switch the calibration mode off: {}", e);
+                }
+            });
+        }
+    }
+}
+
+// Collects at least `frames` frames of the pulse in microvolts, without lost packets in between
+async fn capture_pulse(
+    samples: &mut SampleSubscription,
+    frames: usize,
+    id: &str,
+) -> Result<Vec<SampleBlock>, CalibrationError> {
+    let capture = async {
+        let mut blocks: Vec<SampleBlock> = Vec::new();
+        while blocks.iter().map(SampleBlock::frames).sum::<usize>() < frames {
+            match samples.next().await {
+                Some(StreamItem::Block(block)) if is_pulse(&block) => blocks.push(block),
+                // The period is measured across blocks, so the capture starts over
+                Some(_) => blocks.clear(),
+                None => return Err(CalibrationError::NotFound(id.to_string())),
+            }
+        }
+        Ok(blocks)
+    };
+    match tokio::time::timeout(CAPTURE_TIMEOUT, capture).await {
+        Ok(blocks) => blocks,
+        Err(_) => Err(CalibrationError::Timeout(CAPTURE_TIMEOUT)),
+    }
+}
+
+// The pulse is measured in microvolts, blocks from before the config was known stay out
+fn is_pulse(block: &SampleBlock) -> bool {
+    block.flags.contains(StatusFlags::CALIBRATION) && block.unit == SampleUnit::Microvolts
+}
+
+/// Measures the calibration pulse in blocks converted with `calibration`
+pub fn analyze_pulse(
+    blocks: &[SampleBlock],
+    calibration: &Calibration,
+    sample_rate_hz: u16,
+) -> Result<Vec<ChannelCheck>, CalibrationError> {
+    let mut samples = Vec::new();
+    let mut checks = Vec::with_capacity(calibration.channels.len());
+    for (channel, coefficients) in calibration.channels.iter().enumerate() {
+        samples.clear();
+        samples.extend(blocks.iter().flat_map(|block| block.channel(channel)));
+        let pulse = measure_pulse(&samples).ok_or(CalibrationError::NoPulse(channel))?;
+        let amplitude = pulse.high - pulse.low;
+        let period_ms = pulse.period_samples * 1000.0 / f32::from(sample_rate_hz);
+        let amplitude_counts = amplitude / coefficients.gain_microvolts_per_count;
+        checks.push(ChannelCheck {
+            channel,
+            amplitude_microvolts: amplitude,
+            period_ms,
+            in_tolerance: deviation(amplitude, CALIBRATION_PULSE_MICROVOLTS) <= AMPLITUDE_TOLERANCE
+                && deviation(period_ms, CALIBRATION_PULSE_PERIOD_MS) <= PERIOD_TOLERANCE,
+            // The low level of the pulse is zero
+            corrected: ChannelCalibration {
+                gain_microvolts_per_count: CALIBRATION_PULSE_MICROVOLTS / amplitude_counts,
+                offset_counts: pulse.low / coefficients.gain_microvolts_per_count + coefficients.offset_counts,
+            },
+        });
+    }
+    Ok(checks)
+}
+
+fn deviation(measured: f32, nominal: f32) -> f32 {
+    (measured / nominal - 1.0).abs()
+}
+
+// Levels and period of a square wave
+struct Pulse {
+    low: f32,
+    high: f32,
+    period_samples: f32,
+}
+
+fn measure_pulse(samples: &[f32]) -> Option<Pulse> {
+    let (min, max) = samples
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &s| (min.min(s), max.max(s)));
+    let range = max - min;
+    if range <= 0.0 {
+        return None;
+    }
+    // The outer quarters of the range hold the plateaus, the edges pass through the middle half
+    let lower = min + range / 4.0;
+    let upper = max - range / 4.0;
+    let mean = |plateau: &dyn Fn(f32) -> bool| {
+        let (sum, n) = samples
+            .iter()
+            .filter(|&&s| plateau(s))
+            .fold((0.0, 0), |(sum, n), &s| (sum + s, n + 1));
+        sum / n as f32
+    };
+    // Rising edges, with the middle half as hysteresis against noise
+    let mut rising = Vec::new();
+    let mut low = false;
+    for (i, &s) in samples.iter().enumerate() {
+        if s < lower {
+            low = true;
+        } else if s > upper && low {
+            rising.push(i);
+            low = false;
+        }
+    }
+    let periods = rising.len().checked_sub(1).filter(|&n| n > 0)?;
+    Some(Pulse {
+        low: mean(&|s| s < lower),
+        high: mean(&|s| s > upper),
+        period_samples: (rising[periods] - rising[0]) as f32 / periods as f32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samples::{SampleFormat, StatusFlags};
+    use std::time::SystemTime;
+
+    #[test]
+    fn nominal_and_measured_coefficients() {
+        let layout = PacketLayout {
+            channels: 2,
+            format: SampleFormat::I16Le,
+            frames: 2,
+        };
+        let mut block = SampleBlock {
+            counter: 0,
+            flags: StatusFlags::default(),
+            timestamp: SystemTime::UNIX_EPOCH,
+            channels: 2,
+            unit: SampleUnit::AdcCounts,
+            samples: vec![100.0, -100.0, 32768.0, 10.0],
+            synthetic: vec![false; 4],
+        };
+        let nominal = Calibration::nominal(layout, 4);
+        // 2.4 V full scale over 15 bits, divided by the gain
+        assert_eq!(nominal.channels[0].gain_microvolts_per_count, 2_400_000.0 / 32768.0 / 4.0);
+        let mut converted = block.clone();
+        assert!(nominal.apply(&mut converted));
+        assert_eq!(converted.unit, SampleUnit::Microvolts);
+        assert_eq!(converted.samples[2], 600_000.0);
+        assert!(!nominal.apply(&mut converted));
+
+        let measured = Calibration {
+            source: CalibrationSource::Measured,
+            channels: vec![
+                ChannelCalibration {
+                    gain_microvolts_per_count: 2.0,
+                    offset_counts: 50.0,
+                },
+                ChannelCalibration {
+                    gain_microvolts_per_count: 0.5,
+                    offset_counts: -10.0,
+                },
+            ],
+        };
+        assert!(measured.apply(&mut block));
+        assert_eq!(block.samples, vec![100.0, -45.0, 65436.0, 10.0]);
+    }
+
+    #[tokio::test]
+    async fn calibration_mode_is_switched_off_when_dropped() {
+        use crate::protocol::{self, Command, Frame};
+        use crate::transport::{read_message, Loopback, Pipe};
+        use crate::usb::{spawn_device_loop, COMMAND_PACKET_SIZE};
+        use futures::prelude::*;
+
+        let (host, mut device) = Pipe::pair(COMMAND_PACKET_SIZE);
+        let (loopback, _vis_tx) = Loopback::with_command(host);
+        let (requests, notifications, _control_tx) = spawn_device_loop(loopback, None);
+        let client = HolterClient::new(requests, notifications);
+
+        drop(CalibrationMode(Some(client.share())));
+        let cmd = read_message(&mut device, protocol::frame_len).await.unwrap();
+        let cmd = Frame::decode(&cmd[..]).unwrap();
+        assert_eq!(cmd, Command::SetCalibrationMode { enabled: false }.into_frame(cmd.sequence));
+        let ack = Frame {
+            id: cmd.id | protocol::RESPONSE_FLAG,
+            sequence: cmd.sequence,
+            payload: vec![],
+        };
+        device.write_all(&ack.encode().unwrap()).await.unwrap();
+        device.flush().await.unwrap();
+    }
+
+    #[test]
+    fn measured_calibrations_take_precedence() {
+        let dir = std::env::temp_dir();
+        let factory = dir.join(format!("holter-factory-{}.json", std::process::id()));
+        let measured = dir.join(format!("holter-measured-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&measured);
+        std::fs::write(
+            &factory,
+            r#"{"SN1":{"channels":[{"gainMicrovoltsPerCount":1.0,"offsetCounts":0.0}]},
+                "SN2":{"channels":[{"gainMicrovoltsPerCount":3.0,"offsetCounts":1.0}]}}"#,
+        )
+        .unwrap();
+        let mut store = CalibrationStore::load(Some(&factory), &measured).unwrap();
+        assert_eq!(store.lookup("SN1").unwrap().source, CalibrationSource::Factory);
+        assert!(store.lookup("SN3").is_none());
+
+        let channel = ChannelCalibration {
+            gain_microvolts_per_count: 1.1,
+            offset_counts: 2.0,
+        };
+        let (_, file) = store.store("SN1", vec![channel]);
+        file.unwrap().save().unwrap();
+        assert!(!dir.join(format!("holter-measured-{}.json.tmp", std::process::id())).exists());
+        let reloaded = CalibrationStore::load(Some(&factory), &measured).unwrap();
+        assert_eq!(reloaded.lookup("SN1").unwrap().source, CalibrationSource::Measured);
+        assert_eq!(reloaded.lookup("SN1").unwrap().channels, vec![channel]);
+        assert_eq!(reloaded.lookup("SN2").unwrap().source, CalibrationSource::Factory);
+        std::fs::remove_file(&factory).unwrap();
+        std::fs::remove_file(&measured).unwrap();
+    }
+
+    #[test]
+    fn pulse_measures_and_corrects_channels() {
+        // 250 Hz, 1 Hz pulse. Channel 0 is 3% low, channel 1 is 10% high with an offset of 20
+        // counts, both seen through a calibration of 0.5 uV per count.
+        let previous = Calibration {
+            source: CalibrationSource::Nominal,
+            channels: vec![
+                ChannelCalibration {
+                    gain_microvolts_per_count: 0.5,
+                    offset_counts: 0.0,
+                };
+                2
+            ],
+        };
+        let counts = |frame: usize, channel: usize| {
+            let high = frame % 250 >= 125;
+            let noise = if frame.is_multiple_of(3) { 2.0 } else { -1.0 };
+            match (channel, high) {
+                (0, false) => noise,
+                (0, true) => 1940.0 + noise,
+                (_, false) => 20.0 + noise,
+                (_, true) => 20.0 + 2200.0 + noise,
+            }
+        };
+        let blocks: Vec<SampleBlock> = (0..100)
+            .map(|packet| SampleBlock {
+                counter: packet as u16,
+                flags: StatusFlags(StatusFlags::CALIBRATION),
+                timestamp: SystemTime::UNIX_EPOCH,
+                channels: 2,
+                unit: SampleUnit::Microvolts,
+                samples: (packet * 10..packet * 10 + 10)
+                    .flat_map(|frame| (0..2).map(move |channel| counts(frame, channel) * 0.5))
+                    .collect(),
+                synthetic: vec![false; 20],
+            })
+            .collect();
+
+        let checks = analyze_pulse(&blocks, &previous, 250).unwrap();
+        assert_eq!(checks.len(), 2);
+        assert!((checks[0].amplitude_microvolts - 970.0).abs() < 0.1);
+        assert!((checks[0].period_ms - 1000.0).abs() < 0.1);
+        assert!(checks[0].in_tolerance);
+        assert!((checks[1].amplitude_microvolts - 1100.0).abs() < 0.1);
+        assert!(!checks[1].in_tolerance);
+        // The noise averages out on the plateaus
+        for (check, high) in checks.iter().zip([1940.0, 2220.0].iter()) {
+            let corrected = check.corrected;
+            let pulse = (high - corrected.offset_counts) * corrected.gain_microvolts_per_count;
+            assert!((pulse - 1000.0).abs() < 0.5, "channel {} reads {} uV", check.channel, pulse);
+        }
+        assert!((checks[1].corrected.offset_counts - 20.0).abs() < 0.1);
+
+        let mut flat = blocks[0].clone();
+        flat.samples = vec![0.0; 20];
+        let flat = vec![flat; 10];
+        assert!(matches!(analyze_pulse(&flat, &previous, 250), Err(CalibrationError::NoPulse(0))));
+    }
+}
@@ -0,0 +1,517 @@
+use crate::clock::{self, ClockCorrection, ClockSample, SharedClockHistory};
+use crate::config::{ConfigDiff, ConfigError, DeviceConfig, Profile};
+use crate::protocol::{
+    Battery, Command, Notification, RecordingBlock, RecordingChecksum, RecordingInfo, Response, Status,
+    Storage,
+};
+use crate::usb::{Request, RequestError, USBDevices, DEFAULT_REQUEST_TIMEOUT};
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::convert::TryFrom;
+use std::sync::PoisonError;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    #[error("no device {0} plugged in")]
+    NotFound(String),
+    #[error("failed to acquire {id}: {reason}")]
+    Acquire { id: String, reason: String },
+    #[error("the device loop is gone")]
+    Closed,
+    #[error(transparent)]
+    Request(#[from] RequestError),
+    #[error("command {command_id:#04x} refused with code {code}")]
+    Refused { command_id: u8, code: u8 },
+    #[error("unexpected response: {0:?}")]
+    UnexpectedResponse(Response),
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(#[from] ConfigError),
+    #[error("the device reads back {0:?} after it was configured")]
+    ConfigNotApplied(DeviceConfig),
+    #[error("the device clock is still {0} ms off after it was set")]
+    ClockNotSet(i64),
+}
+
+/// Typed access to one monitor. Commands are sent through the device loop of an acquired device,
+/// so several clients may share a device and their commands are pipelined.
+pub struct HolterClient {
+    requests: mpsc::Sender<Request>,
+    notifications: Option<mpsc::Receiver<Notification>>,
+    timeout: Duration,
+    clock: SharedClockHistory,
+    // Set when the client acquired the device itself, so that `release` can give it back
+    acquired: Option<(USBDevices, String)>,
+}
+
+impl HolterClient {
+    /// Acquires the device with the given id
+    pub async fn acquire(usb_devices: &USBDevices, id: &str) -> Result<Self, ClientError> {
+        let history = usb_devices.clock_history(id).await;
+        match usb_devices.acquire_device(id).await {
+            Ok(Some((requests, notifications))) => {
+                let mut client = HolterClient::new(requests, notifications);
+                if let Some(history) = history {
+                    client.set_clock_history(history);
+                }
+                client.acquired = Some((usb_devices.clone(), id.to_string()));
+                Ok(client)
+            }
+            Ok(None) => Err(ClientError::NotFound(id.to_string())),
+            Err(e) => Err(ClientError::Acquire {
+                id: id.to_string(),
+                reason: e.to_string(),
+            }),
+        }
+    }
+
+    /// Wraps the channels of a device that was acquired elsewhere
+    pub fn new(
+        requests: mpsc::Sender<Request>,
+        notifications: mpsc::Receiver<Notification>,
+    ) -> Self {
+        let mut client = HolterClient::from_requests(requests);
+        client.notifications = Some(notifications);
+        client
+    }
+
+    /// Sends commands through the requests of a device used elsewhere, so that both can talk to
+    /// it. Notifications stay with the other user.
+    pub fn from_requests(requests: mpsc::Sender<Request>) -> Self {
+        HolterClient {
+            requests,
+            notifications: None,
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            clock: Default::default(),
+            acquired: None,
+        }
+    }
+
+    /// Another client sending through the same device loop. Notifications stay with this one.
+    pub fn share(&self) -> Self {
+        let mut client = HolterClient::from_requests(self.requests.clone());
+        client.timeout = self.timeout;
+        client.clock = self.clock.clone();
+        client
+    }
+
+    /// Records clock syncs in `history` rather than in a history of this client alone. With the
+    /// history of the device from `USBDevices::clock_history` the drift is measured across all
+    /// syncs of the device, whichever client ran them.
+    pub fn set_clock_history(&mut self, history: SharedClockHistory) {
+        self.clock = history;
+    }
+
+    /// Drift of the device clock over the syncs recorded so far, see `sync_clock`
+    pub fn clock_drift_ppm(&self) -> Option<f64> {
+        self.clock.lock().unwrap_or_else(PoisonError::into_inner).drift_ppm()
+    }
+
+    /// Time the device has to answer each command
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Takes the stream of notifications. Only the first call returns it.
+    pub fn notifications(&mut self) -> Option<mpsc::Receiver<Notification>> {
+        self.notifications.take()
+    }
+
+    pub async fn status(&mut self) -> Result<Status, ClientError> {
+        match self.request(Command::GetStatus).await? {
+            Response::Status(status) => Ok(status),
+            res => Err(ClientError::UnexpectedResponse(res)),
+        }
+    }
+
+    pub async fn battery(&mut self) -> Result<Battery, ClientError> {
+        match self.request(Command::GetBattery).await? {
+            Response::Battery(battery) => Ok(battery),
+            res => Err(ClientError::UnexpectedResponse(res)),
+        }
+    }
+
+    pub async fn storage(&mut self) -> Result<Storage, ClientError> {
+        match self.request(Command::GetStorage).await? {
+            Response::Storage(storage) => Ok(storage),
+            res => Err(ClientError::UnexpectedResponse(res)),
+        }
+    }
+
+    pub async fn config(&mut self) -> Result<DeviceConfig, ClientError> {
+        match self.request(Command::GetConfig).await? {
+            Response::Config(config) => Ok(config),
+            res => Err(ClientError::UnexpectedResponse(res)),
+        }
+    }
+
+    /// Validates and stores `config`, then reads it back to make sure the device took it
+    pub async fn set_config(&mut self, config: &DeviceConfig) -> Result<(), ClientError> {
+        config.validate()?;
+        self.write_config(config).await
+    }
+
+    // Writes `config` as is and reads it back
+    async fn write_config(&mut self, config: &DeviceConfig) -> Result<(), ClientError> {
+        self.command(Command::SetConfig(config.clone())).await?;
+        let stored = self.config().await?;
+        if stored != *config {
+            return Err(ClientError::ConfigNotApplied(stored));
+        }
+        Ok(())
+    }
+
+    /// Settings of the device that applying `profile` would change
+    pub async fn diff_profile(&mut self, profile: &Profile) -> Result<Vec<ConfigDiff>, ClientError> {
+        Ok(profile.diff(&self.config().await?))
+    }
+
+    /// Applies `profile` and returns the settings it changed. Nothing is written when the device
+    /// already matches. Only the settings of the profile are validated, the patient id of the
+    /// device is kept as it is, even when none is set yet.
+    pub async fn apply_profile(&mut self, profile: &Profile) -> Result<Vec<ConfigDiff>, ClientError> {
+        profile.validate()?;
+        let config = self.config().await?;
+        let diff = profile.diff(&config);
+        if !diff.is_empty() {
+            self.write_config(&profile.apply(&config)).await?;
+            info!("Applied profile {}: {} settings changed", profile.name, diff.len());
+        }
+        Ok(diff)
+    }
+
+    /// Feeds the calibration pulse instead of the electrodes to the live stream, see
+    /// `calibration::self_check`
+    pub async fn set_calibration_mode(&mut self, enabled: bool) -> Result<(), ClientError> {
+        self.command(Command::SetCalibrationMode { enabled }).await
+    }
+
+    pub async fn start_recording(&mut self) -> Result<(), ClientError> {
+        self.command(Command::StartRecording).await
+    }
+
+    pub async fn stop_recording(&mut self) -> Result<(), ClientError> {
+        self.command(Command::StopRecording).await
+    }
+
+    /// Deletes all recordings on the device
+    pub async fn erase_memory(&mut self) -> Result<(), ClientError> {
+        self.command(Command::EraseMemory).await
+    }
+
+    pub async fn recordings(&mut self) -> Result<Vec<RecordingInfo>, ClientError> {
+        match self.request(Command::ListRecordings).await? {
+            Response::Recordings { recordings } => Ok(recordings),
+            res => Err(ClientError::UnexpectedResponse(res)),
+        }
+    }
+
+    /// Reads `len` bytes of a recording starting at `offset`. The block is not checked against
+    /// its CRC, see `Download` for that.
+    pub async fn read_recording(
+        &mut self,
+        recording_id: u16,
+        offset: u32,
+        len: u32,
+    ) -> Result<RecordingBlock, ClientError> {
+        let command = Command::ReadRecording {
+            recording_id,
+            offset,
+            len,
+        };
+        match self.request(command).await? {
+            Response::Block(block) => Ok(block),
+            res => Err(ClientError::UnexpectedResponse(res)),
+        }
+    }
+
+    pub async fn recording_checksum(&mut self, recording_id: u16) -> Result<RecordingChecksum, ClientError> {
+        match self.request(Command::GetRecordingChecksum { recording_id }).await? {
+            Response::Checksum(checksum) => Ok(checksum),
+            res => Err(ClientError::UnexpectedResponse(res)),
+        }
+    }
+
+    /// Reads the device clock and measures its offset from the host clock
+    pub async fn measure_clock(&mut self) -> Result<ClockSample, ClientError> {
+        let sent = SystemTime::now();
+        match self.request(Command::GetTime).await? {
+            Response::Time { unix_millis } => {
+                Ok(ClockSample::new(sent, SystemTime::now(), unix_millis))
+            }
+            res => Err(ClientError::UnexpectedResponse(res)),
+        }
+    }
+
+    /// Sets the device clock to the host clock. The drift since the previous sync is measured
+    /// first, and the resulting correction is stored in the recording metadata on the device.
+    pub async fn sync_clock(&mut self) -> Result<ClockCorrection, ClientError> {
+        let before = self.measure_clock().await?;
+        self.clock.lock().unwrap_or_else(PoisonError::into_inner).record_drift(&before);
+
+        // The device sets its clock about half a round trip after the command was sent
+        let now = SystemTime::now() + before.round_trip / 2;
+        let unix_millis = clock::unix_millis(now);
+        self.command(Command::SetTime { unix_millis }).await?;
+
+        let after = self.measure_clock().await?;
+        let correction = self.clock.lock().unwrap_or_else(PoisonError::into_inner).record_sync(&after);
+        let offset_millis = i32::try_from(correction.offset_millis)
+            .map_err(|_| ClientError::ClockNotSet(correction.offset_millis))?;
+        self.command(Command::SetClockCorrection {
+            offset_millis,
+            drift_ppb: correction.drift_ppb(),
+        })
+        .await?;
+        info!(
+            "Clock synced, offset {} ms, drift {:?} ppm",
+            correction.offset_millis, correction.drift_ppm
+        );
+        Ok(correction)
+    }
+
+    /// Sends any command and returns the response. A refusal is returned as an error.
+    pub async fn request(&mut self, command: Command) -> Result<Response, ClientError> {
+        let (req, reply_rx) = Request::with_timeout(command, self.timeout);
+        if self.requests.send(req).await.is_err() {
+            return Err(ClientError::Closed);
+        }
+        match reply_rx.await {
+            Ok(Ok(Response::Nack { command_id, code })) => {
+                Err(ClientError::Refused { command_id, code })
+            }
+            Ok(Ok(res)) => Ok(res),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(ClientError::Closed),
+        }
+    }
+
+    // Sends a command that is answered with an Ack
+    async fn command(&mut self, command: Command) -> Result<(), ClientError> {
+        let id = command.id();
+        match self.request(command).await? {
+            Response::Ack { command_id } if command_id == id => Ok(()),
+            res => Err(ClientError::UnexpectedResponse(res)),
+        }
+    }
+
+    /// Releases the device if this client acquired it
+    pub async fn release(self) {
+        if let Some((usb_devices, id)) = self.acquired {
+            usb_devices.release_device(&id).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{self, Frame};
+    use crate::transport::{read_message, Loopback, Pipe};
+    use crate::usb::{spawn_device_loop, Control, COMMAND_PACKET_SIZE};
+
+    // Answers every command on `device` with the frame built by `answer`
+    fn simulate<F>(mut device: Pipe, answer: F)
+    where
+        F: Fn(&Frame) -> Frame + Send + 'static,
+    {
+        tokio::spawn(async move {
+            while let Ok(msg) = read_message(&mut device, protocol::frame_len).await {
+                let cmd = Frame::decode(&msg[..]).unwrap();
+                let res = answer(&cmd).encode().unwrap();
+                device.write_all(&res[..]).await.unwrap();
+                device.flush().await.unwrap();
+            }
+        });
+    }
+
+    // The device loop ends once the returned control channel is dropped
+    fn client(
+        answer: impl Fn(&Frame) -> Frame + Send + 'static,
+    ) -> (HolterClient, mpsc::Sender<Control>) {
+        let (host, device) = Pipe::pair(COMMAND_PACKET_SIZE);
+        simulate(device, answer);
+        let (loopback, _vis_tx) = Loopback::with_command(host);
+        let (requests, notifications, control_tx) = spawn_device_loop(loopback, None);
+        (HolterClient::new(requests, notifications), control_tx)
+    }
+
+    #[tokio::test]
+    async fn typed_results() {
+        let (mut client, _control_tx) = client(|cmd| {
+            let payload = match cmd.id {
+                0x04 => vec![0x00, 0x10, 0, 0, 0x00, 0x00, 0x01, 0],
+                0x20 => vec![1, 0, 0x10, 0, 0, 0, 60, 0, 0, 0, 0x00, 0x04, 0, 0],
+                _ => vec![],
+            };
+            Frame {
+                id: cmd.id | protocol::RESPONSE_FLAG,
+                sequence: cmd.sequence,
+                payload,
+            }
+        });
+        assert_eq!(
+            client.storage().await,
+            Ok(Storage {
+                used_bytes: 0x1000,
+                total_bytes: 0x10000
+            })
+        );
+        assert_eq!(
+            client.recordings().await,
+            Ok(vec![RecordingInfo {
+                id: 1,
+                start_time: 0x10,
+                duration_secs: 60,
+                size_bytes: 0x400,
+            }])
+        );
+        assert_eq!(client.start_recording().await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn sync_sets_device_clock() {
+        use std::sync::{Arc, Mutex};
+
+        // The device clock runs 5 s ahead until it is set
+        let offset = Arc::new(Mutex::new(5000i64));
+        let corrections = Arc::new(Mutex::new(Vec::new()));
+        let (mut client, _control_tx) = client({
+            let corrections = Arc::clone(&corrections);
+            move |cmd| {
+                let host = clock::unix_millis(SystemTime::now()) as i64;
+                let mut offset = offset.lock().unwrap();
+                let payload = match cmd.id {
+                    0x05 => ((host + *offset) as u64).to_le_bytes().to_vec(),
+                    0x06 => {
+                        let mut millis = [0u8; 8];
+                        millis.copy_from_slice(&cmd.payload[..]);
+                        *offset = u64::from_le_bytes(millis) as i64 - host;
+                        vec![]
+                    }
+                    _ => {
+                        corrections.lock().unwrap().push(cmd.payload.clone());
+                        vec![]
+                    }
+                };
+                Frame {
+                    id: cmd.id | protocol::RESPONSE_FLAG,
+                    sequence: cmd.sequence,
+                    payload,
+                }
+            }
+        });
+        let history = SharedClockHistory::default();
+        client.set_clock_history(Arc::clone(&history));
+        let before = client.measure_clock().await.unwrap();
+        assert!((before.offset_millis - 5000).abs() < 100);
+        let correction = client.sync_clock().await.unwrap();
+        assert!(correction.offset_millis.abs() < 100);
+        assert_eq!(correction.drift_ppm, None);
+        assert_eq!(corrections.lock().unwrap().len(), 1);
+
+        // Another client of the device measures the drift since the first sync
+        let mut later = HolterClient::from_requests(client.requests.clone());
+        later.set_clock_history(history);
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        assert!(later.sync_clock().await.unwrap().drift_ppm.is_some());
+        assert_eq!(corrections.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn sync_fails_when_clock_is_not_set() {
+        // The device ignores SetTime and stays 30 days ahead, more than a correction can hold
+        let offset = 30 * 24 * 3600 * 1000i64;
+        let (mut client, _control_tx) = client(move |cmd| {
+            let host = clock::unix_millis(SystemTime::now()) as i64;
+            let payload = match cmd.id {
+                0x05 => ((host + offset) as u64).to_le_bytes().to_vec(),
+                0x06 => vec![],
+                id => panic!("unexpected command {:#04x}", id),
+            };
+            Frame {
+                id: cmd.id | protocol::RESPONSE_FLAG,
+                sequence: cmd.sequence,
+                payload,
+            }
+        });
+        match client.sync_clock().await {
+            Err(ClientError::ClockNotSet(millis)) => assert!((millis - offset).abs() < 100),
+            result => panic!("expected the clock not to be set, got {:?}", result),
+        }
+    }
+
+    // Answers GetConfig with the last config written, starting with `stored`
+    fn stored_config(stored: Vec<u8>) -> impl Fn(&Frame) -> Frame + Send + 'static {
+        let stored = std::sync::Mutex::new(stored);
+        move |cmd| {
+            let mut stored = stored.lock().unwrap();
+            let payload = match cmd.id {
+                0x08 => stored.clone(),
+                _ => {
+                    *stored = cmd.payload.clone();
+                    vec![]
+                }
+            };
+            Frame {
+                id: cmd.id | protocol::RESPONSE_FLAG,
+                sequence: cmd.sequence,
+                payload,
+            }
+        }
+    }
+
+    fn profile_48h() -> Profile {
+        Profile {
+            name: "holter-48h".to_string(),
+            sample_rate_hz: 250,
+            lead_set: crate::config::LeadSet::FiveLead,
+            gain: 4,
+            recording_hours: 48,
+        }
+    }
+
+    #[tokio::test]
+    async fn apply_profile_reads_back() {
+        // 250 Hz, 3 leads, gain 4, 24 h, patient "P1"
+        let (mut client, _control_tx) = client(stored_config(vec![0xfa, 0x00, 3, 4, 24, 2, b'P', b'1']));
+        let profile = profile_48h();
+        let changed = client.apply_profile(&profile).await.unwrap();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(client.diff_profile(&profile).await, Ok(vec![]));
+        assert_eq!(client.config().await.unwrap().patient_id, "P1");
+
+        let mut invalid = client.config().await.unwrap();
+        invalid.gain = 5;
+        assert_eq!(
+            client.set_config(&invalid).await,
+            Err(ClientError::InvalidConfig(ConfigError::Gain(5)))
+        );
+    }
+
+    #[tokio::test]
+    async fn apply_profile_without_patient_id() {
+        // 250 Hz, 3 leads, gain 4, 24 h, no patient yet
+        let (mut client, _control_tx) = client(stored_config(vec![0xfa, 0x00, 3, 4, 24, 0]));
+        let changed = client.apply_profile(&profile_48h()).await.unwrap();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(client.config().await.unwrap().patient_id, "");
+    }
+
+    #[tokio::test]
+    async fn refused_command() {
+        let (mut client, _control_tx) = client(|cmd| Frame {
+            id: protocol::NACK_ID,
+            sequence: cmd.sequence,
+            payload: vec![cmd.id, 3],
+        });
+        assert_eq!(
+            client.erase_memory().await,
+            Err(ClientError::Refused {
+                command_id: 0x12,
+                code: 3
+            })
+        );
+    }
+}
@@ -0,0 +1,137 @@
+// Bookkeeping for synchronizing the real-time clock of a monitor, see `HolterClient::sync_clock`
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The `ClockHistory` of one monitor, shared by all its clients, see `USBDevices::clock_history`
+pub type SharedClockHistory = Arc<Mutex<ClockHistory>>;
+
+/// One reading of the device clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSample {
+    /// Host time at which the device read its clock, estimated as the middle of the round trip
+    pub host_time: SystemTime,
+    /// Device clock minus host clock, in milliseconds
+    pub offset_millis: i64,
+    pub round_trip: Duration,
+}
+
+impl ClockSample {
+    /// Builds a sample from a clock reading requested at `sent` and answered at `received`
+    pub fn new(sent: SystemTime, received: SystemTime, device_millis: u64) -> Self {
+        let round_trip = received.duration_since(sent).unwrap_or_default();
+        let host_time = sent + round_trip / 2;
+        ClockSample {
+            host_time,
+            offset_millis: device_millis as i64 - unix_millis(host_time) as i64,
+            round_trip,
+        }
+    }
+}
+
+/// Correction to apply to the device clock, stored in the recording metadata
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClockCorrection {
+    /// Device clock minus host clock right after the sync, in milliseconds
+    pub offset_millis: i64,
+    /// How fast the device clock runs compared to the host clock, in parts per million. None
+    /// until the clock has been synced twice.
+    pub drift_ppm: Option<f64>,
+    /// Round trip of the reading the offset is based on, in milliseconds
+    pub round_trip_millis: u64,
+}
+
+impl ClockCorrection {
+    /// Drift in parts per billion, the unit the device stores
+    pub fn drift_ppb(&self) -> i32 {
+        self.drift_ppm.map(|ppm| (ppm * 1000.0).round() as i32).unwrap_or(0)
+    }
+}
+
+/// Drift of one device clock over successive syncs. It is only kept in memory, so the drift is
+/// measured anew after the bridge restarts.
+#[derive(Debug, Clone, Default)]
+pub struct ClockHistory {
+    // Host time and offset right after the last sync
+    last_sync: Option<(SystemTime, i64)>,
+    // Sum of the time between syncs and of the offset gained over it, in milliseconds
+    elapsed_millis: f64,
+    drift_millis: f64,
+}
+
+impl ClockHistory {
+    /// Records the offset measured right before a sync, which is what the clock drifted since the
+    /// previous one
+    pub fn record_drift(&mut self, before: &ClockSample) {
+        if let Some((time, offset)) = self.last_sync {
+            if let Ok(elapsed) = before.host_time.duration_since(time) {
+                self.elapsed_millis += elapsed.as_millis() as f64;
+                self.drift_millis += (before.offset_millis - offset) as f64;
+            }
+        }
+    }
+
+    /// Records the offset measured right after a sync and returns the correction for it
+    pub fn record_sync(&mut self, after: &ClockSample) -> ClockCorrection {
+        self.last_sync = Some((after.host_time, after.offset_millis));
+        ClockCorrection {
+            offset_millis: after.offset_millis,
+            drift_ppm: self.drift_ppm(),
+            round_trip_millis: after.round_trip.as_millis() as u64,
+        }
+    }
+
+    /// Drift over all syncs so far, weighted by the time between them
+    pub fn drift_ppm(&self) -> Option<f64> {
+        if self.elapsed_millis > 0.0 {
+            Some(self.drift_millis / self.elapsed_millis * 1e6)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drift_over_successive_syncs() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let sample = |secs: u64, offset_millis: i64| ClockSample {
+            host_time: start + Duration::from_secs(secs),
+            offset_millis,
+            round_trip: Duration::from_millis(4),
+        };
+        let mut history = ClockHistory::default();
+
+        history.record_drift(&sample(0, 250));
+        let correction = history.record_sync(&sample(0, 1));
+        assert_eq!(correction.offset_millis, 1);
+        assert_eq!(correction.drift_ppm, None);
+
+        // 10 ms fast after 1000 s, then 20 ms after another 2000 s
+        history.record_drift(&sample(1000, 11));
+        history.record_sync(&sample(1000, 0));
+        history.record_drift(&sample(3000, 20));
+        let correction = history.record_sync(&sample(3000, -1));
+        assert_eq!(correction.drift_ppm, Some(10.0));
+        assert_eq!(correction.drift_ppb(), 10_000);
+    }
+
+    #[test]
+    fn sample_offset_from_round_trip() {
+        let sent = UNIX_EPOCH + Duration::from_millis(10_000);
+        let received = sent + Duration::from_millis(20);
+        let sample = ClockSample::new(sent, received, 10_510);
+        assert_eq!(sample.offset_millis, 500);
+        assert_eq!(sample.round_trip, Duration::from_millis(20));
+    }
+}
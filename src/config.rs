@@ -0,0 +1,247 @@
+//! Configuration of a monitor and named profiles of settings to apply to many monitors.
+//!
+//! A profile holds everything but the patient id, which differs from one monitor to the next.
+use crate::client::{ClientError, HolterClient};
+use futures::future::join_all;
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::path::Path;
+use thiserror::Error;
+
+/// Sample rates the monitor supports
+pub const SAMPLE_RATES_HZ: [u16; 4] = [125, 250, 500, 1000];
+/// Gains the front end supports
+pub const GAINS: [u8; 7] = [1, 2, 3, 4, 6, 8, 12];
+/// Longest recording that fits in the memory of the monitor
+pub const MAX_RECORDING_HOURS: u8 = 48;
+/// Longest patient id the monitor stores
+pub const MAX_PATIENT_ID_LEN: usize = 16;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("unsupported sample rate {0} Hz")]
+    SampleRate(u16),
+    #[error("unsupported gain {0}")]
+    Gain(u8),
+    #[error("recording duration must be 1 to {} hours, got {0}", MAX_RECORDING_HOURS)]
+    RecordingHours(u8),
+    #[error("patient id must be 1 to {} letters, digits, '-' or '_', got {0:?}", MAX_PATIENT_ID_LEN)]
+    PatientId(String),
+}
+
+/// Electrodes the monitor records from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LeadSet {
+    ThreeLead,
+    FiveLead,
+    TwelveLead,
+}
+
+impl LeadSet {
+    /// Number of electrodes, which is how the monitor encodes the lead set
+    pub fn electrodes(self) -> u8 {
+        match self {
+            LeadSet::ThreeLead => 3,
+            LeadSet::FiveLead => 5,
+            LeadSet::TwelveLead => 12,
+        }
+    }
+
+    pub fn from_electrodes(electrodes: u8) -> Option<Self> {
+        match electrodes {
+            3 => Some(LeadSet::ThreeLead),
+            5 => Some(LeadSet::FiveLead),
+            12 => Some(LeadSet::TwelveLead),
+            _ => None,
+        }
+    }
+}
+
+/// Settings stored on the monitor
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceConfig {
+    pub sample_rate_hz: u16,
+    pub lead_set: LeadSet,
+    pub gain: u8,
+    pub recording_hours: u8,
+    pub patient_id: String,
+}
+
+impl DeviceConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        validate_settings(self.sample_rate_hz, self.gain, self.recording_hours)?;
+        let id = &self.patient_id;
+        if id.is_empty()
+            || id.len() > MAX_PATIENT_ID_LEN
+            || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(ConfigError::PatientId(id.clone()));
+        }
+        Ok(())
+    }
+}
+
+fn validate_settings(sample_rate_hz: u16, gain: u8, recording_hours: u8) -> Result<(), ConfigError> {
+    if !SAMPLE_RATES_HZ.contains(&sample_rate_hz) {
+        return Err(ConfigError::SampleRate(sample_rate_hz));
+    }
+    if !GAINS.contains(&gain) {
+        return Err(ConfigError::Gain(gain));
+    }
+    if recording_hours == 0 || recording_hours > MAX_RECORDING_HOURS {
+        return Err(ConfigError::RecordingHours(recording_hours));
+    }
+    Ok(())
+}
+
+/// Named set of settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub name: String,
+    pub sample_rate_hz: u16,
+    pub lead_set: LeadSet,
+    pub gain: u8,
+    pub recording_hours: u8,
+}
+
+/// One setting that differs between a monitor and a profile
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigDiff {
+    pub field: &'static str,
+    pub device: Value,
+    pub profile: Value,
+}
+
+impl Profile {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        validate_settings(self.sample_rate_hz, self.gain, self.recording_hours)
+    }
+
+    /// The configuration of the monitor once the profile is applied
+    pub fn apply(&self, config: &DeviceConfig) -> DeviceConfig {
+        DeviceConfig {
+            sample_rate_hz: self.sample_rate_hz,
+            lead_set: self.lead_set,
+            gain: self.gain,
+            recording_hours: self.recording_hours,
+            patient_id: config.patient_id.clone(),
+        }
+    }
+
+    /// Settings of `config` that applying the profile would change
+    pub fn diff(&self, config: &DeviceConfig) -> Vec<ConfigDiff> {
+        let mut diff = Vec::new();
+        push_diff(&mut diff, "sampleRateHz", config.sample_rate_hz, self.sample_rate_hz);
+        push_diff(&mut diff, "leadSet", config.lead_set, self.lead_set);
+        push_diff(&mut diff, "gain", config.gain, self.gain);
+        push_diff(&mut diff, "recordingHours", config.recording_hours, self.recording_hours);
+        diff
+    }
+}
+
+fn push_diff<T: PartialEq + Serialize>(diff: &mut Vec<ConfigDiff>, field: &'static str, device: T, profile: T) {
+    if device != profile {
+        diff.push(ConfigDiff {
+            field,
+            device: serde_json::to_value(device).unwrap_or_default(),
+            profile: serde_json::to_value(profile).unwrap_or_default(),
+        });
+    }
+}
+
+/// Reads a JSON array of profiles. Every profile is validated and names must be unique.
+pub fn load_profiles(path: &Path) -> Result<Vec<Profile>, Box<dyn std::error::Error>> {
+    let profiles: Vec<Profile> = serde_json::from_slice(&std::fs::read(path)?)?;
+    let mut names = HashSet::new();
+    for profile in &profiles {
+        profile
+            .validate()
+            .map_err(|e| format!("profile {}: {}", profile.name, e))?;
+        if !names.insert(&profile.name) {
+            return Err(format!("profile {} defined twice", profile.name).into());
+        }
+    }
+    Ok(profiles)
+}
+
+/// Applies `profile` to all devices in `ids` at once, each through the client `client_for` gives
+/// for it. Every client is released once its device is done, e.g. a client from
+/// `HolterClient::acquire` gives its device back. Returns the settings changed on each device.
+pub async fn apply_bulk<F, C>(
+    ids: &[String],
+    profile: &Profile,
+    client_for: F,
+) -> Vec<(String, Result<Vec<ConfigDiff>, ClientError>)>
+where
+    F: Fn(&str) -> C,
+    C: Future<Output = Result<HolterClient, ClientError>>,
+{
+    let applied = ids.iter().map(|id| {
+        let client = client_for(id);
+        async move {
+            let result = match client.await {
+                Ok(mut client) => {
+                    let result = client.apply_profile(profile).await;
+                    client.release().await;
+                    result
+                }
+                Err(e) => Err(e),
+            };
+            (id.clone(), result)
+        }
+    });
+    join_all(applied).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DeviceConfig {
+        DeviceConfig {
+            sample_rate_hz: 250,
+            lead_set: LeadSet::ThreeLead,
+            gain: 4,
+            recording_hours: 24,
+            patient_id: "P-0042".to_string(),
+        }
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(config().validate(), Ok(()));
+        let mut invalid = config();
+        invalid.sample_rate_hz = 300;
+        assert_eq!(invalid.validate(), Err(ConfigError::SampleRate(300)));
+        let mut invalid = config();
+        invalid.recording_hours = 72;
+        assert_eq!(invalid.validate(), Err(ConfigError::RecordingHours(72)));
+        let mut invalid = config();
+        invalid.patient_id = "Jane Doe".to_string();
+        assert!(matches!(invalid.validate(), Err(ConfigError::PatientId(_))));
+    }
+
+    #[test]
+    fn profile_diff_keeps_patient_id() {
+        let profile = Profile {
+            name: "48h".to_string(),
+            sample_rate_hz: 250,
+            lead_set: LeadSet::FiveLead,
+            gain: 4,
+            recording_hours: 48,
+        };
+        let diff = profile.diff(&config());
+        let fields: Vec<_> = diff.iter().map(|d| d.field).collect();
+        assert_eq!(fields, vec!["leadSet", "recordingHours"]);
+        assert_eq!(diff[0].profile, Value::from("fiveLead"));
+        let applied = profile.apply(&config());
+        assert_eq!(applied.patient_id, "P-0042");
+        assert!(profile.diff(&applied).is_empty());
+    }
+}
@@ -0,0 +1,115 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Everything the bridge knows about one monitor. This is the schema shared by the REST API, the
+/// CLI and the logs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    /// Stable id, the serial number or the port chain if the device has none
+    pub id: String,
+    /// bus:address while the device is plugged in
+    pub path: Option<String>,
+    /// Hub ports from the root hub to the device
+    pub port_chain: Vec<u8>,
+    pub speed: Speed,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// bcdDevice of the device descriptor
+    pub firmware_version: FirmwareVersion,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub state: AcquisitionState,
+    /// Last time the device was seen on the bus, in milliseconds since the unix epoch
+    #[serde(with = "unix_millis")]
+    pub last_seen: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Speed {
+    Unknown,
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+impl From<libusb::Speed> for Speed {
+    fn from(speed: libusb::Speed) -> Self {
+        match speed {
+            libusb::Speed::Low => Speed::Low,
+            libusb::Speed::Full => Speed::Full,
+            libusb::Speed::High => Speed::High,
+            libusb::Speed::Super => Speed::Super,
+            libusb::Speed::Unknown => Speed::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub sub_minor: u8,
+}
+
+impl From<libusb::Version> for FirmwareVersion {
+    fn from(version: libusb::Version) -> Self {
+        FirmwareVersion {
+            major: version.major(),
+            minor: version.minor(),
+            sub_minor: version.sub_minor(),
+        }
+    }
+}
+
+impl std::fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.sub_minor)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AcquisitionState {
+    Available,
+    Acquired,
+    /// Acquired, but the device was plugged out. Requests fail until it comes back, then the
+    /// session resumes.
+    Suspended,
+}
+
+/// Changes to the device registry, see `USBDevices::subscribe`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum DeviceEvent {
+    /// A device was plugged in, either for the first time or coming back
+    Arrived(DeviceInfo),
+    /// A device was plugged out
+    Left { id: String },
+    Acquired { id: String },
+    Released { id: String },
+    /// The subscriber did not keep up and missed events. `USBDevices::devices` has the current
+    /// state.
+    Lagged { missed: u64 },
+}
+
+mod unix_millis {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let millis = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        serializer.serialize_u64(millis)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let millis = u64::deserialize(deserializer)?;
+        Ok(UNIX_EPOCH + Duration::from_millis(millis))
+    }
+}
@@ -0,0 +1,371 @@
+//! Download of recordings stored on the monitor over the bulk data channel.
+//!
+//! A recording is read block by block. Each block is checked against the CRC the device sent
+//! along before it is appended to `<path>.part`, so the part file only ever holds verified data
+//! and a later download of the same recording resumes where it ends. Once all blocks are in, the
+//! part file is checked against the CRC of the whole recording and renamed to `path`.
+//!
+//! Web clients start downloads over a websocket of their own and follow the progress, the files
+//! go to the download directory of the bridge. See scripts/EXAMPLES.md.
+use crate::client::{ClientError, HolterClient};
+use crate::protocol::{self, RecordingInfo};
+use crate::usb::RequestError;
+use futures::channel::mpsc;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Size of the blocks a recording is read in, one transfer on the data channel
+pub const BLOCK_LEN: u32 = protocol::MAX_BLOCK_LEN;
+// Times a block is requested before the download gives up
+const BLOCK_ATTEMPTS: u32 = 5;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DownloadError {
+    #[error(transparent)]
+    Client(#[from] ClientError),
+    #[error("I/O error: {0}")]
+    Io(String),
+    #[error("block at offset {offset} failed {attempts} times")]
+    Block { offset: u32, attempts: u32 },
+    #[error("device reports {actual} bytes for a recording of {expected} bytes")]
+    SizeMismatch { expected: u32, actual: u32 },
+    #[error("recording checksum mismatch: device says {expected:#010x}, file has {actual:#010x}")]
+    Checksum { expected: u32, actual: u32 },
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(e: io::Error) -> Self {
+        DownloadError::Io(e.to_string())
+    }
+}
+
+/// Sent whenever a block has been verified and written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadProgress {
+    pub recording_id: u16,
+    pub verified_bytes: u32,
+    pub total_bytes: u32,
+}
+
+/// Download of one recording to a file
+pub struct Download {
+    recording: RecordingInfo,
+    path: PathBuf,
+    progress: Option<mpsc::UnboundedSender<DownloadProgress>>,
+}
+
+impl Download {
+    pub fn new(recording: RecordingInfo, path: impl Into<PathBuf>) -> Self {
+        Download {
+            recording,
+            path: path.into(),
+            progress: None,
+        }
+    }
+
+    /// Stream of progress events. The first event reports what was kept from an earlier
+    /// attempt.
+    pub fn progress(&mut self) -> mpsc::UnboundedReceiver<DownloadProgress> {
+        let (tx, rx) = mpsc::unbounded();
+        self.progress = Some(tx);
+        rx
+    }
+
+    /// File the recording is downloaded to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// File holding the verified part of the recording until the download is complete
+    pub fn part_path(&self) -> PathBuf {
+        let mut part = self.path.clone().into_os_string();
+        part.push(".part");
+        PathBuf::from(part)
+    }
+
+    /// Downloads the recording, resuming from the part file left by an earlier attempt.
+    /// Failed blocks are requested again, which also rides out the device being unplugged for a
+    /// moment. When the download still fails, calling `run` again resumes it.
+    pub async fn run(&self, client: &mut HolterClient) -> Result<(), DownloadError> {
+        let id = self.recording.id;
+        let size = self.recording.size_bytes;
+        let part_path = self.part_path();
+        let (mut part, mut offset, mut crc) = {
+            let part_path = part_path.clone();
+            blocking(move || open_part(&part_path, size)).await?
+        };
+        if offset > 0 {
+            info!("Resuming download of recording {} at {} of {} bytes", id, offset, size);
+        }
+        self.report(offset);
+
+        // The file is written off the executor, the CRC of the recording is kept up to date on
+        // the way
+        while offset < size {
+            let len = u32::min(BLOCK_LEN, size - offset);
+            let data = self.read_block(client, offset, len).await?;
+            crc = protocol::crc32(crc, &data[..]);
+            part = blocking(move || {
+                part.write_all(&data[..])?;
+                part.sync_data()?;
+                Ok(part)
+            })
+            .await?;
+            offset += len;
+            self.report(offset);
+        }
+        blocking(move || {
+            drop(part);
+            Ok(())
+        })
+        .await?;
+
+        let checksum = client.recording_checksum(id).await?;
+        if checksum.size_bytes != size {
+            return Err(DownloadError::SizeMismatch {
+                expected: size,
+                actual: checksum.size_bytes,
+            });
+        }
+        if crc != checksum.crc32 {
+            // The blocks were fine, so the recording changed between attempts. Start over next
+            // time.
+            blocking(move || fs::remove_file(&part_path)).await?;
+            return Err(DownloadError::Checksum {
+                expected: checksum.crc32,
+                actual: crc,
+            });
+        }
+        let path = self.path.clone();
+        blocking(move || fs::rename(&part_path, &path)).await?;
+        info!("Recording {} downloaded to {}", id, self.path.display());
+        Ok(())
+    }
+
+    // Reads one block, requesting it again until it matches its CRC
+    async fn read_block(
+        &self,
+        client: &mut HolterClient,
+        offset: u32,
+        len: u32,
+    ) -> Result<Vec<u8>, DownloadError> {
+        let id = self.recording.id;
+        for attempt in 1..=BLOCK_ATTEMPTS {
+            match client.read_recording(id, offset, len).await {
+                Ok(block) if block.recording_id != id || block.offset != offset || block.len != len => {
+                    warn!("Block at {} answered with {:?}, retrying", offset, (block.offset, block.len));
+                }
+                Ok(block) if protocol::crc32(0, &block.data[..]) != block.crc32 => {
+                    warn!("Block at {} failed its checksum, attempt {}", offset, attempt);
+                }
+                Ok(block) => return Ok(block.data),
+                Err(ClientError::Request(e)) if is_transient(&e) => {
+                    warn!("Reading block at {} failed: {}, attempt {}", offset, e, attempt);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(DownloadError::Block {
+            offset,
+            attempts: BLOCK_ATTEMPTS,
+        })
+    }
+
+    fn report(&self, verified_bytes: u32) {
+        if let Some(progress) = &self.progress {
+            let _ = progress.unbounded_send(DownloadProgress {
+                recording_id: self.recording.id,
+                verified_bytes,
+                total_bytes: self.recording.size_bytes,
+            });
+        }
+    }
+}
+
+fn is_transient(e: &RequestError) -> bool {
+    matches!(
+        e,
+        RequestError::Timeout(_) | RequestError::Disconnected | RequestError::Io(_) | RequestError::Protocol(_)
+    )
+}
+
+// Runs file system calls on the blocking pool
+async fn blocking<T, F>(f: F) -> Result<T, DownloadError>
+where
+    F: FnOnce() -> Result<T, io::Error> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => Ok(result?),
+        Err(e) => Err(DownloadError::Io(e.to_string())),
+    }
+}
+
+// Opens the part file, positioned at its end. Only whole blocks count, a block may have been
+// written partly when the host went down. Returns the file with the bytes kept and their CRC-32,
+// read back from disk.
+fn open_part(path: &Path, size: u32) -> Result<(File, u32, u32), io::Error> {
+    let mut part = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    let kept = part.metadata()?.len().min(size as u64) as u32;
+    let kept = kept - kept % BLOCK_LEN;
+    part.set_len(kept as u64)?;
+    let crc = file_crc32(&mut part)?;
+    Ok((part, kept, crc))
+}
+
+// CRC-32 of the whole file, which leaves the file at its end
+fn file_crc32(file: &mut File) -> Result<u32, io::Error> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut buf = vec![0u8; BLOCK_LEN as usize];
+    let mut crc = 0;
+    loop {
+        match file.read(&mut buf[..])? {
+            0 => return Ok(crc),
+            len => crc = protocol::crc32(crc, &buf[..len]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Frame;
+    use crate::transport::{read_message, Loopback, Pipe, LOOPBACK_DATA_PACKET_SIZE};
+    use crate::usb::{spawn_device_loop, Control, COMMAND_PACKET_SIZE};
+    use futures::prelude::*;
+    use std::sync::{Arc, Mutex};
+
+    // Serves `recording` as recording 1. Blocks listed in `corrupt` are sent with a flipped bit
+    // the first time, the offsets of all block requests are recorded in `requested`.
+    fn device(
+        recording: Vec<u8>,
+        checksum: u32,
+        corrupt: Vec<u32>,
+        requested: Arc<Mutex<Vec<u32>>>,
+    ) -> (HolterClient, mpsc::Sender<Control>) {
+        let (host, mut command) = Pipe::pair(COMMAND_PACKET_SIZE);
+        let (host_data, mut data) = Pipe::pair(LOOPBACK_DATA_PACKET_SIZE);
+        tokio::spawn(async move {
+            let mut corrupt = corrupt;
+            while let Ok(msg) = read_message(&mut command, protocol::frame_len).await {
+                let cmd = Frame::decode(&msg[..]).unwrap();
+                let mut block = None;
+                let payload = match cmd.id {
+                    0x21 => {
+                        let p = &cmd.payload;
+                        let offset = u32::from_le_bytes([p[2], p[3], p[4], p[5]]);
+                        let len = u32::from_le_bytes([p[6], p[7], p[8], p[9]]);
+                        requested.lock().unwrap().push(offset);
+                        let mut data = recording[offset as usize..(offset + len) as usize].to_vec();
+                        let mut payload = p.clone();
+                        payload.extend_from_slice(&protocol::crc32(0, &data[..]).to_le_bytes());
+                        if let Some(i) = corrupt.iter().position(|&o| o == offset) {
+                            corrupt.remove(i);
+                            data[0] ^= 0x01;
+                        }
+                        block = Some(data);
+                        payload
+                    }
+                    _ => {
+                        let mut payload = vec![1, 0];
+                        payload.extend_from_slice(&(recording.len() as u32).to_le_bytes());
+                        payload.extend_from_slice(&checksum.to_le_bytes());
+                        payload
+                    }
+                };
+                let res = Frame {
+                    id: cmd.id | protocol::RESPONSE_FLAG,
+                    sequence: cmd.sequence,
+                    payload,
+                };
+                command.write_all(&res.encode().unwrap()[..]).await.unwrap();
+                command.flush().await.unwrap();
+                if let Some(block) = block {
+                    data.write_all(&block[..]).await.unwrap();
+                    data.flush().await.unwrap();
+                }
+            }
+        });
+        let (loopback, _vis_tx) = Loopback::with_pipes(host, host_data);
+        let (requests, notifications, control_tx) = spawn_device_loop(loopback, None);
+        (HolterClient::new(requests, notifications), control_tx)
+    }
+
+    fn recording(len: usize) -> (RecordingInfo, Vec<u8>) {
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 + i / 256) as u8).collect();
+        let info = RecordingInfo {
+            id: 1,
+            start_time: 0,
+            duration_secs: 60,
+            size_bytes: len as u32,
+        };
+        (info, data)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("holter-{}-{}.bin", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn download_with_progress() {
+        let (info, data) = recording(40_000);
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let checksum = protocol::crc32(0, &data[..]);
+        let (mut client, _control_tx) = device(data.clone(), checksum, vec![16384], Arc::clone(&requested));
+        let path = temp_path("download");
+        let mut download = Download::new(info, &path);
+        let progress = download.progress();
+
+        download.run(&mut client).await.unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert!(!download.part_path().exists());
+        // The corrupted block was requested again
+        assert_eq!(*requested.lock().unwrap(), vec![0, 16384, 16384, 32768]);
+        drop(download);
+        let verified: Vec<u32> = progress.map(|p| p.verified_bytes).collect().await;
+        assert_eq!(verified, vec![0, 16384, 32768, 40000]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_from_verified_blocks() {
+        let (info, data) = recording(40_000);
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let checksum = protocol::crc32(0, &data[..]);
+        let (mut client, _control_tx) = device(data.clone(), checksum, vec![], Arc::clone(&requested));
+        let path = temp_path("resume");
+        let download = Download::new(info, &path);
+        // One whole block and part of the next one were written before
+        fs::write(download.part_path(), &data[..20_000]).unwrap();
+
+        download.run(&mut client).await.unwrap();
+        assert_eq!(*requested.lock().unwrap(), vec![16384, 32768]);
+        assert_eq!(fs::read(&path).unwrap(), data);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn whole_file_checksum_mismatch() {
+        let (info, data) = recording(1000);
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let (mut client, _control_tx) = device(data, 0x1234_5678, vec![], requested);
+        let path = temp_path("mismatch");
+        let download = Download::new(info, &path);
+
+        let result = download.run(&mut client).await;
+        assert!(matches!(result, Err(DownloadError::Checksum { expected: 0x1234_5678, .. })));
+        assert!(!path.exists());
+        assert!(!download.part_path().exists());
+    }
+}
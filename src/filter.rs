@@ -0,0 +1,434 @@
+//! Digital filters for the live ECG stream.
+//!
+//! A `FilterChain` runs every channel of a block through the same stages, each channel with its
+//! own state, so that a trace continues smoothly from one packet to the next. Filters are chosen
+//! per consumer, see `SampleSubscription::filter`. Blocks are filtered in place; memory is only
+//! allocated when the chain is built or the number of channels changes.
+use crate::samples::{SampleBlock, SampleUnit};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use thiserror::Error;
+
+/// Longest FIR filter, in taps
+pub const MAX_FIR_TAPS: usize = 255;
+/// Most stages in a chain
+pub const MAX_STAGES: usize = 8;
+// Quality of the mains notch, about 1.7 Hz wide at 50 Hz
+const DEFAULT_NOTCH_Q: f32 = 30.0;
+// Quality of a second order Butterworth filter
+const BUTTERWORTH_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum FilterError {
+    #[error("{frequency_hz} Hz is not between 0 and the Nyquist frequency of {nyquist_hz} Hz")]
+    Frequency { frequency_hz: f32, nyquist_hz: f32 },
+    #[error("notch quality must be positive, got {0}")]
+    Quality(f32),
+    #[error("FIR filters need an odd number of taps up to {}, got {0}", MAX_FIR_TAPS)]
+    Taps(usize),
+    #[error("a chain has at most {} stages, got {0}", MAX_STAGES)]
+    Stages(usize),
+}
+
+/// One stage of a filter chain
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "filter", rename_all = "camelCase")]
+pub enum FilterSpec {
+    /// Second order Butterworth high-pass against baseline wander. 0.5 Hz suits monitoring,
+    /// 0.05 Hz keeps the ST segment intact.
+    #[serde(rename_all = "camelCase")]
+    Highpass { cutoff_hz: f32 },
+    /// Notch against mains interference at 50 or 60 Hz
+    #[serde(rename_all = "camelCase")]
+    Notch {
+        frequency_hz: f32,
+        #[serde(default = "default_notch_q")]
+        q: f32,
+    },
+    /// Second order Butterworth low-pass against muscle noise
+    #[serde(rename_all = "camelCase")]
+    Lowpass { cutoff_hz: f32 },
+    /// Linear phase low-pass, a Hamming windowed sinc. It delays the signal by half its taps.
+    #[serde(rename_all = "camelCase")]
+    FirLowpass { cutoff_hz: f32, taps: usize },
+}
+
+fn default_notch_q() -> f32 {
+    DEFAULT_NOTCH_Q
+}
+
+impl FilterSpec {
+    /// The usual chain for monitoring: 0.5 Hz high-pass, notch at the mains frequency and 40 Hz
+    /// low-pass
+    pub fn monitoring(mains_hz: f32) -> Vec<FilterSpec> {
+        vec![
+            FilterSpec::Highpass { cutoff_hz: 0.5 },
+            FilterSpec::Notch {
+                frequency_hz: mains_hz,
+                q: DEFAULT_NOTCH_Q,
+            },
+            FilterSpec::Lowpass { cutoff_hz: 40.0 },
+        ]
+    }
+
+    fn design(&self, sample_rate_hz: u16) -> Result<Stage, FilterError> {
+        let fs = f64::from(sample_rate_hz);
+        let check = |frequency_hz: f32| {
+            let nyquist_hz = f32::from(sample_rate_hz) / 2.0;
+            if frequency_hz > 0.0 && frequency_hz < nyquist_hz {
+                Ok(f64::from(frequency_hz))
+            } else {
+                Err(FilterError::Frequency {
+                    frequency_hz,
+                    nyquist_hz,
+                })
+            }
+        };
+        match *self {
+            FilterSpec::Highpass { cutoff_hz } => {
+                Ok(Stage::Iir(Biquad::highpass(check(cutoff_hz)? / fs, BUTTERWORTH_Q)))
+            }
+            FilterSpec::Notch { frequency_hz, q } => {
+                if q.is_nan() || q <= 0.0 {
+                    return Err(FilterError::Quality(q));
+                }
+                Ok(Stage::Iir(Biquad::notch(check(frequency_hz)? / fs, f64::from(q))))
+            }
+            FilterSpec::Lowpass { cutoff_hz } => {
+                Ok(Stage::Iir(Biquad::lowpass(check(cutoff_hz)? / fs, BUTTERWORTH_Q)))
+            }
+            FilterSpec::FirLowpass { cutoff_hz, taps } => {
+                if taps % 2 != 1 || taps > MAX_FIR_TAPS {
+                    return Err(FilterError::Taps(taps));
+                }
+                Ok(Stage::Fir(Fir::lowpass(check(cutoff_hz)? / fs, taps)))
+            }
+        }
+    }
+}
+
+/// Filters blocks of one device, see the module documentation
+#[derive(Debug, Clone)]
+pub struct FilterChain {
+    specs: Vec<FilterSpec>,
+    // The stages of one channel as designed, copied for every channel
+    prototype: Vec<Stage>,
+    // The stages of every channel, one channel after the other
+    stages: Vec<Stage>,
+    channels: usize,
+    // Unit of the blocks the state was built from
+    unit: Option<SampleUnit>,
+}
+
+impl FilterChain {
+    /// Designs the stages for the sample rate of the device
+    pub fn new(specs: &[FilterSpec], sample_rate_hz: u16) -> Result<Self, FilterError> {
+        if specs.len() > MAX_STAGES {
+            return Err(FilterError::Stages(specs.len()));
+        }
+        let prototype = specs
+            .iter()
+            .map(|spec| spec.design(sample_rate_hz))
+            .collect::<Result<_, _>>()?;
+        Ok(FilterChain {
+            specs: specs.to_vec(),
+            prototype,
+            stages: Vec::new(),
+            channels: 0,
+            unit: None,
+        })
+    }
+
+    pub fn specs(&self) -> &[FilterSpec] {
+        &self.specs
+    }
+
+    /// Forgets the past samples. The next block starts the filters as if its first sample had
+    /// been there forever, which keeps an electrode offset from ringing through the high-pass.
+    pub fn reset(&mut self) {
+        for stage in &mut self.stages {
+            stage.reset();
+        }
+    }
+
+    /// Filters a block in place. The state is reset when the unit changes, the number of
+    /// channels may change too but that costs an allocation.
+    pub fn process(&mut self, block: &mut SampleBlock) {
+        if block.channels == 0 || self.prototype.is_empty() {
+            return;
+        }
+        if block.channels != self.channels {
+            self.channels = block.channels;
+            self.stages.clear();
+            for _ in 0..block.channels {
+                self.stages.extend_from_slice(&self.prototype);
+            }
+            self.reset();
+        }
+        if self.unit != Some(block.unit) {
+            self.unit = Some(block.unit);
+            self.reset();
+        }
+        let depth = self.prototype.len();
+        for (i, sample) in block.samples.iter_mut().enumerate() {
+            let channel = i % block.channels;
+            let stages = &mut self.stages[channel * depth..(channel + 1) * depth];
+            *sample = stages.iter_mut().fold(*sample, |x, stage| stage.process(x));
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Stage {
+    Iir(Biquad),
+    Fir(Fir),
+}
+
+impl Stage {
+    fn process(&mut self, x: f32) -> f32 {
+        match self {
+            Stage::Iir(biquad) => biquad.process(x),
+            Stage::Fir(fir) => fir.process(x),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            Stage::Iir(biquad) => biquad.primed = false,
+            Stage::Fir(fir) => fir.primed = false,
+        }
+    }
+}
+
+// Second order section in transposed direct form II. Coefficients are normalized to a0 and, like
+// the state, kept in f64: a 0.05 Hz high-pass has poles too close to 1 for an f32.
+#[derive(Debug, Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+    primed: bool,
+}
+
+impl Biquad {
+    // Designs from the audio EQ cookbook, `f` is the frequency over the sample rate
+    fn highpass(f: f64, q: f64) -> Self {
+        let (cos, alpha) = Biquad::prewarp(f, q);
+        let b = [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0];
+        Biquad::normalized(b, [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+
+    fn lowpass(f: f64, q: f64) -> Self {
+        let (cos, alpha) = Biquad::prewarp(f, q);
+        let b = [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0];
+        Biquad::normalized(b, [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+
+    fn notch(f: f64, q: f64) -> Self {
+        let (cos, alpha) = Biquad::prewarp(f, q);
+        Biquad::normalized([1.0, -2.0 * cos, 1.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+
+    fn prewarp(f: f64, q: f64) -> (f64, f64) {
+        let w0 = 2.0 * PI * f;
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Self {
+        Biquad {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            z: [0.0; 2],
+            primed: false,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let x = f64::from(x);
+        let [b0, b1, b2] = self.b;
+        let [a1, a2] = self.a;
+        if !self.primed {
+            // The state the filter settles in when x never changes
+            let y = x * (b0 + b1 + b2) / (1.0 + a1 + a2);
+            self.z = [y - b0 * x, b2 * x - a2 * y];
+            self.primed = true;
+        }
+        let y = b0 * x + self.z[0];
+        self.z = [b1 * x - a1 * y + self.z[1], b2 * x - a2 * y];
+        y as f32
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Fir {
+    taps: Vec<f32>,
+    // The last samples, `next` is where the coming one goes
+    history: Vec<f32>,
+    next: usize,
+    primed: bool,
+}
+
+impl Fir {
+    // Windowed sinc with unity gain at DC, `f` is the cutoff over the sample rate
+    fn lowpass(f: f64, len: usize) -> Self {
+        let middle = (len / 2) as f64;
+        let mut taps: Vec<f64> = (0..len)
+            .map(|i| {
+                let n = i as f64 - middle;
+                let sinc = if n == 0.0 {
+                    2.0 * f
+                } else {
+                    (2.0 * PI * f * n).sin() / (PI * n)
+                };
+                let window = if len == 1 {
+                    1.0
+                } else {
+                    0.54 - 0.46 * (2.0 * PI * i as f64 / (len - 1) as f64).cos()
+                };
+                sinc * window
+            })
+            .collect();
+        let sum: f64 = taps.iter().sum();
+        taps.iter_mut().for_each(|tap| *tap /= sum);
+        Fir {
+            taps: taps.into_iter().map(|tap| tap as f32).collect(),
+            history: vec![0.0; len],
+            next: 0,
+            primed: false,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        if !self.primed {
+            self.history.iter_mut().for_each(|past| *past = x);
+            self.primed = true;
+        }
+        self.history[self.next] = x;
+        self.next = (self.next + 1) % self.history.len();
+        // The oldest sample is at `next`
+        let (older, newer) = self.history.split_at(self.next);
+        newer
+            .iter()
+            .chain(older)
+            .zip(&self.taps)
+            .map(|(x, tap)| x * tap)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samples::StatusFlags;
+    use std::time::SystemTime;
+
+    const RATE: u16 = 500;
+
+    // One channel of a sine in microvolts, cut into packets of 10 samples
+    fn sine(frequency_hz: f32, amplitude: f32, offset: f32, seconds: usize) -> Vec<SampleBlock> {
+        let samples: Vec<f32> = (0..seconds * RATE as usize)
+            .map(|i| {
+                let t = i as f32 / f32::from(RATE);
+                offset + amplitude * (2.0 * std::f32::consts::PI * frequency_hz * t).sin()
+            })
+            .collect();
+        samples
+            .chunks(10)
+            .enumerate()
+            .map(|(counter, chunk)| SampleBlock {
+                counter: counter as u16,
+                flags: StatusFlags::default(),
+                timestamp: SystemTime::UNIX_EPOCH,
+                channels: 1,
+                unit: SampleUnit::Microvolts,
+                samples: chunk.to_vec(),
+                synthetic: vec![false; chunk.len()],
+            })
+            .collect()
+    }
+
+    // Largest sample in the last second, once the filters have settled
+    fn filtered_peak(chain: &mut FilterChain, mut blocks: Vec<SampleBlock>) -> f32 {
+        blocks.iter_mut().for_each(|block| chain.process(block));
+        let last_second = blocks.len() - RATE as usize / 10;
+        blocks[last_second..]
+            .iter()
+            .flat_map(|block| block.samples.iter())
+            .fold(0.0, |peak: f32, s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn monitoring_chain_passes_ecg_band() {
+        let mut chain = FilterChain::new(&FilterSpec::monitoring(50.0), RATE).unwrap();
+        // An electrode offset of 300 mV with mains hum on top
+        assert!(filtered_peak(&mut chain, sine(50.0, 500.0, 300_000.0, 4)) < 5.0);
+        chain.reset();
+        assert!(filtered_peak(&mut chain, sine(0.05, 2000.0, 0.0, 4)) < 30.0);
+        chain.reset();
+        let peak = filtered_peak(&mut chain, sine(10.0, 1000.0, 0.0, 4));
+        assert!((peak - 1000.0).abs() < 20.0, "10 Hz comes out at {} uV", peak);
+        chain.reset();
+        assert!(filtered_peak(&mut chain, sine(150.0, 1000.0, 0.0, 4)) < 100.0);
+    }
+
+    #[test]
+    fn state_carries_over_packets() {
+        let specs = [
+            FilterSpec::Highpass { cutoff_hz: 0.5 },
+            FilterSpec::FirLowpass {
+                cutoff_hz: 40.0,
+                taps: 31,
+            },
+        ];
+        let blocks = sine(7.0, 1000.0, 1500.0, 1);
+        let mut whole = SampleBlock {
+            samples: blocks.iter().flat_map(|block| block.samples.clone()).collect(),
+            synthetic: Vec::new(),
+            ..blocks[0].clone()
+        };
+        FilterChain::new(&specs, RATE).unwrap().process(&mut whole);
+        let mut chain = FilterChain::new(&specs, RATE).unwrap();
+        let mut packets = blocks;
+        packets.iter_mut().for_each(|block| chain.process(block));
+        let joined: Vec<f32> = packets.iter().flat_map(|block| block.samples.clone()).collect();
+        assert_eq!(joined, whole.samples);
+
+        // A steady input comes out unchanged from the first sample after a reset
+        let mut lowpass = FilterChain::new(&specs[1..], RATE).unwrap();
+        let mut steady = SampleBlock {
+            samples: vec![-800.0; 40],
+            ..whole
+        };
+        lowpass.process(&mut steady);
+        assert!(steady.samples.iter().all(|&s| (s + 800.0).abs() < 0.01));
+    }
+
+    #[test]
+    fn rejects_impossible_filters() {
+        assert_eq!(
+            FilterChain::new(&[FilterSpec::Lowpass { cutoff_hz: 130.0 }], 250).unwrap_err(),
+            FilterError::Frequency {
+                frequency_hz: 130.0,
+                nyquist_hz: 125.0
+            }
+        );
+        let fir = FilterSpec::FirLowpass {
+            cutoff_hz: 40.0,
+            taps: 32,
+        };
+        assert_eq!(FilterChain::new(&[fir], 250).unwrap_err(), FilterError::Taps(32));
+        let highpass = FilterSpec::Highpass { cutoff_hz: 0.5 };
+        assert_eq!(
+            FilterChain::new(&[highpass; MAX_STAGES + 1], 250).unwrap_err(),
+            FilterError::Stages(MAX_STAGES + 1)
+        );
+        let spec: FilterSpec = serde_json::from_str(r#"{"filter":"notch","frequencyHz":60}"#).unwrap();
+        assert_eq!(
+            spec,
+            FilterSpec::Notch {
+                frequency_hz: 60.0,
+                q: DEFAULT_NOTCH_Q
+            }
+        );
+    }
+}
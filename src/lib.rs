@@ -0,0 +1,18 @@
+#[macro_use]
+extern crate log;
+
+pub mod calibration;
+pub mod client;
+pub mod clock;
+pub mod config;
+pub mod deviceinfo;
+pub mod download;
+pub mod filter;
+pub mod protocol;
+mod rawusb;
+pub mod samples;
+pub mod stream;
+pub mod transport;
+pub mod usb;
+pub mod usbfutures;
+pub mod web;
@@ -1,23 +1,21 @@
 
 use futures::channel::mpsc;
-use futures::prelude::*;
 use std::net::SocketAddr;
+use std::path::Path;
 use tokio::runtime::Runtime;
 
 #[macro_use]
 extern crate log;
 
-//mod error;
-mod usb;
-//mod web;
-mod usbfutures;
-
-use usb::USBDevices;
+use holter_bridge::calibration::CalibrationStore;
+use holter_bridge::config;
+use holter_bridge::usb::USBDevices;
+use holter_bridge::web;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Check if the user requested some specific log level via an env variable. Otherwise set log
     // level to something reasonable.
-    if let Ok(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_ok() {
         env_logger::init();
     } else {
         env_logger::Builder::new()
@@ -37,8 +35,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // execute.
     let (mut notify_tx, notify_rx) = mpsc::channel(1);
     // Trigger one refresh on startup
-    //web::notify(&mut notify_tx);
-    notify_tx.try_send(()).expect("Startup trigger not worked");
+    web::notify(&mut notify_tx);
 
     // Create and spawn the future that polls for USB devices
     let usb_poller = {
@@ -50,47 +47,45 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
-    let echo = {
-        let usb_devices = usb_devices.clone();
+    // Log every change to the set of devices
+    let event_logger = {
+        let mut events = usb_devices.subscribe();
         async move {
-            tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
-            let list = usb_devices.devices().await;
-            info!("List of devices: {:#?}", &list);
-            assert!(list.len() > 0, "No devices in list");
-
-            let dev = usb_devices.acquire_device(&list[0]["path"]).await;
-            let mut echo = vec![0x0u8;1];
-            loop {
-                if let Ok(Some((mut tx, mut rx))) = dev {
-                    loop {
-                        info!("Echo In : {:x?}", &echo);
-                        tx.send(echo.clone()).await.expect("Echo: cant not send");
-                        match rx.next().await {
-                            Some(r) => {
-                                echo[0] = r[0];
-                                info!("Echo Out : {:x?}", r)
-                            }
-                            None => error!("Echo: no recive data"),
-                        };
-                        tokio::time::delay_for(std::time::Duration::from_millis(250)).await; 
-                    }
-                }
-                else { error!("Echo: no device channels") }
-                tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+            while let Some(event) = events.next().await {
+                info!("Device event: {}", serde_json::to_string(&event).unwrap_or_default());
             }
         }
     };
 
+    // Configuration profiles, from the file named by HOLTER_PROFILES or profiles.json if present
+    let profiles = match std::env::var("HOLTER_PROFILES") {
+        Ok(path) => config::load_profiles(path.as_ref())?,
+        Err(_) if Path::new("profiles.json").exists() => config::load_profiles("profiles.json".as_ref())?,
+        Err(_) => Vec::new(),
+    };
+    info!("Loaded {} configuration profiles", profiles.len());
+
+    // Calibrations measured on site are kept in the file named by HOLTER_CALIBRATION, the factory
+    // table is read from HOLTER_FACTORY_CALIBRATION if set
+    let measured = std::env::var("HOLTER_CALIBRATION").unwrap_or_else(|_| "calibration.json".to_string());
+    let factory = std::env::var("HOLTER_FACTORY_CALIBRATION").ok();
+    let calibrations = CalibrationStore::load(factory.as_ref().map(Path::new), measured.as_ref())?;
+    rt.block_on(usb_devices.set_calibrations(calibrations));
+
+    // Recordings are downloaded to the directory named by HOLTER_DOWNLOADS, or to downloads in the
+    // working directory
+    let download_dir = std::env::var("HOLTER_DOWNLOADS").unwrap_or_else(|_| "downloads".to_string());
+
     let addr: SocketAddr = "127.0.0.1:3333".parse()?;
 
     println!("listening on http://{}", addr);
-    //let server = web::create(usb_devices, notify_tx, addr);
+    let server = web::create(usb_devices, notify_tx, profiles, download_dir.into(), addr);
 
     rt.block_on(async move {
         tokio::select! {
-            //_ = server => info!("Warp returned"),
+            _ = server => info!("Warp returned"),
             _ = usb_poller => info!("Usb poller died"),
-            _ = echo => info!("Echo ended"),
+            _ = event_logger => info!("Event logger ended"),
         }
     });
 
@@ -0,0 +1,656 @@
+//! Framing of the command channel (EP_OUT/EP_IN).
+//!
+//! Every command and every response is one frame: a 6 byte header followed by the payload.
+//!
+//! | offset | size | field                                          |
+//! |--------|------|------------------------------------------------|
+//! | 0      | 1    | command id, responses have `RESPONSE_FLAG` set |
+//! |        |      | notifications have `NOTIFICATION_FLAG` set     |
+//! | 1      | 1    | sequence number, echoed in the response        |
+//! | 2      | 2    | payload length, little endian                  |
+//! | 4      | 2    | CRC-16/CCITT-FALSE of the id, sequence number, length and payload, little endian |
+//!
+//! All multi byte fields are little endian. Notifications are sent by the device on its own, their
+//! sequence number has no meaning.
+//!
+//! The response to `ReadRecording` only describes the block, the block itself follows on the bulk
+//! data channel (EP_DATA_IN).
+use crate::config::{DeviceConfig, LeadSet};
+use crate::usb::MAX_MESSAGE_LEN;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub const HEADER_LEN: usize = 6;
+/// Longest payload that fits in one frame
+pub const MAX_PAYLOAD_LEN: usize = MAX_MESSAGE_LEN - HEADER_LEN;
+/// Set in the id of every response, the other bits are the id of the command
+pub const RESPONSE_FLAG: u8 = 0x80;
+/// Id of the response to a command the device refused
+pub const NACK_ID: u8 = 0xff;
+/// Set in the id of notifications, with `RESPONSE_FLAG` cleared
+pub const NOTIFICATION_FLAG: u8 = 0x40;
+/// Longest block a `ReadRecording` may ask for, one transfer on the data channel
+pub const MAX_BLOCK_LEN: u32 = 16 * 1024;
+
+const CMD_PING: u8 = 0x01;
+const CMD_GET_STATUS: u8 = 0x02;
+const CMD_GET_BATTERY: u8 = 0x03;
+const CMD_GET_STORAGE: u8 = 0x04;
+const CMD_GET_TIME: u8 = 0x05;
+const CMD_SET_TIME: u8 = 0x06;
+const CMD_SET_CLOCK_CORRECTION: u8 = 0x07;
+const CMD_GET_CONFIG: u8 = 0x08;
+const CMD_SET_CONFIG: u8 = 0x09;
+const CMD_SET_CALIBRATION_MODE: u8 = 0x0a;
+const CMD_START_RECORDING: u8 = 0x10;
+const CMD_STOP_RECORDING: u8 = 0x11;
+const CMD_ERASE_MEMORY: u8 = 0x12;
+const CMD_LIST_RECORDINGS: u8 = 0x20;
+const CMD_READ_RECORDING: u8 = 0x21;
+const CMD_GET_RECORDING_CHECKSUM: u8 = 0x22;
+
+// Size of one entry in the response to CMD_LIST_RECORDINGS
+const RECORDING_INFO_LEN: usize = 14;
+
+const NOTIFY_LEAD_OFF: u8 = 0x41;
+const NOTIFY_LOW_BATTERY: u8 = 0x42;
+const NOTIFY_EVENT_BUTTON: u8 = 0x43;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("frame too short: {0} bytes")]
+    TooShort(usize),
+    #[error("frame length mismatch: header says {expected} bytes, got {actual}")]
+    LengthMismatch { expected: usize, actual: usize },
+    #[error("CRC mismatch: header says {expected:#06x}, computed {actual:#06x}")]
+    Crc { expected: u16, actual: u16 },
+    #[error("payload of {0} bytes does not fit in a frame")]
+    PayloadTooLong(usize),
+    #[error("malformed payload for id {id:#04x}")]
+    Malformed { id: u8 },
+    #[error("id {0:#04x} is reserved for responses and notifications")]
+    ReservedId(u8),
+    #[error("block of {announced} bytes announced for a read of {requested} bytes")]
+    BlockLength { requested: u32, announced: u32 },
+    #[error("block of {0} bytes is longer than {} bytes", MAX_BLOCK_LEN)]
+    BlockTooLong(u32),
+}
+
+/// One frame on the command channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub id: u8,
+    pub sequence: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        if self.payload.len() > MAX_PAYLOAD_LEN {
+            return Err(Error::PayloadTooLong(self.payload.len()));
+        }
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.push(self.id);
+        buf.push(self.sequence);
+        buf.extend_from_slice(&(self.payload.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&self.payload[..]);
+        let crc = frame_crc(&buf[..]);
+        buf[4..HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let expected = match frame_len(buf) {
+            Some(expected) => expected,
+            None => return Err(Error::TooShort(buf.len())),
+        };
+        if buf.len() != expected {
+            return Err(Error::LengthMismatch {
+                expected,
+                actual: buf.len(),
+            });
+        }
+        let crc = u16::from_le_bytes([buf[4], buf[5]]);
+        let actual = frame_crc(buf);
+        if crc != actual {
+            return Err(Error::Crc {
+                expected: crc,
+                actual,
+            });
+        }
+        Ok(Frame {
+            id: buf[0],
+            sequence: buf[1],
+            payload: buf[HEADER_LEN..].to_vec(),
+        })
+    }
+
+    /// Whether the device sent this frame on its own rather than in response to a command
+    pub fn is_notification(&self) -> bool {
+        self.id & (RESPONSE_FLAG | NOTIFICATION_FLAG) == NOTIFICATION_FLAG
+    }
+
+    /// Whether this frame is the response, or the refusal, of the command `command_id`
+    pub fn answers(&self, command_id: u8) -> bool {
+        match self.id {
+            NACK_ID => self.payload.first() == Some(&command_id),
+            id => id == command_id | RESPONSE_FLAG,
+        }
+    }
+}
+
+/// Length of the whole frame starting at `buf`, once enough of the header has been received
+pub fn frame_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < HEADER_LEN {
+        return None;
+    }
+    Some(HEADER_LEN + u16::from_le_bytes([buf[2], buf[3]]) as usize)
+}
+
+// CRC over the encoded frame, skipping the CRC field itself
+fn frame_crc(buf: &[u8]) -> u16 {
+    let crc = crc16(0xffff, &buf[..4]);
+    crc16(crc, &buf[HEADER_LEN..])
+}
+
+/// CRC-32/ISO-HDLC, as used by zlib, of recording blocks and whole recordings. Pass the CRC of
+/// the preceding data, or 0 at the start, to compute it piecewise.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// CRC-16/CCITT-FALSE
+fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Commands understood by the monitor
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "camelCase")]
+pub enum Command {
+    Ping,
+    GetStatus,
+    GetBattery,
+    GetStorage,
+    /// Reads the real-time clock
+    GetTime,
+    /// Sets the real-time clock, in milliseconds since the unix epoch
+    #[serde(rename_all = "camelCase")]
+    SetTime { unix_millis: u64 },
+    /// Stores the clock correction in the metadata of the recordings made from now on.
+    /// `drift_ppb` is in parts per billion, positive when the device clock runs fast.
+    #[serde(rename_all = "camelCase")]
+    SetClockCorrection { offset_millis: i32, drift_ppb: i32 },
+    GetConfig,
+    /// Stores the configuration. It is not validated here, see `DeviceConfig::validate`.
+    SetConfig(DeviceConfig),
+    /// Switches the inputs of the front end to its 1 mV calibration pulse, or back to the
+    /// electrodes
+    SetCalibrationMode { enabled: bool },
+    StartRecording,
+    StopRecording,
+    /// Deletes all recordings
+    EraseMemory,
+    ListRecordings,
+    /// Reads `len` bytes of a recording starting at `offset`
+    #[serde(rename_all = "camelCase")]
+    ReadRecording {
+        recording_id: u16,
+        offset: u32,
+        len: u32,
+    },
+    /// Asks for the CRC-32 of a whole recording
+    #[serde(rename_all = "camelCase")]
+    GetRecordingChecksum { recording_id: u16 },
+    /// Any other command, sent as is
+    Raw { id: u8, payload: Vec<u8> },
+}
+
+impl Command {
+    pub fn id(&self) -> u8 {
+        match self {
+            Command::Ping => CMD_PING,
+            Command::GetStatus => CMD_GET_STATUS,
+            Command::GetBattery => CMD_GET_BATTERY,
+            Command::GetStorage => CMD_GET_STORAGE,
+            Command::GetTime => CMD_GET_TIME,
+            Command::SetTime { .. } => CMD_SET_TIME,
+            Command::SetClockCorrection { .. } => CMD_SET_CLOCK_CORRECTION,
+            Command::GetConfig => CMD_GET_CONFIG,
+            Command::SetConfig(_) => CMD_SET_CONFIG,
+            Command::SetCalibrationMode { .. } => CMD_SET_CALIBRATION_MODE,
+            Command::StartRecording => CMD_START_RECORDING,
+            Command::StopRecording => CMD_STOP_RECORDING,
+            Command::EraseMemory => CMD_ERASE_MEMORY,
+            Command::ListRecordings => CMD_LIST_RECORDINGS,
+            Command::ReadRecording { .. } => CMD_READ_RECORDING,
+            Command::GetRecordingChecksum { .. } => CMD_GET_RECORDING_CHECKSUM,
+            Command::Raw { id, .. } => *id,
+        }
+    }
+
+    /// Refuses raw commands with an id the device uses for responses or notifications, their
+    /// answer could not be told apart from one
+    pub fn check_id(&self) -> Result<(), Error> {
+        match self {
+            Command::Raw { id, .. } if id & (RESPONSE_FLAG | NOTIFICATION_FLAG) != 0 => {
+                Err(Error::ReservedId(*id))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn into_frame(self, sequence: u8) -> Frame {
+        let id = self.id();
+        let payload = match self {
+            Command::SetTime { unix_millis } => unix_millis.to_le_bytes().to_vec(),
+            Command::SetClockCorrection {
+                offset_millis,
+                drift_ppb,
+            } => {
+                let mut payload = offset_millis.to_le_bytes().to_vec();
+                payload.extend_from_slice(&drift_ppb.to_le_bytes());
+                payload
+            }
+            Command::SetConfig(config) => encode_config(&config),
+            Command::SetCalibrationMode { enabled } => vec![enabled as u8],
+            Command::ReadRecording {
+                recording_id,
+                offset,
+                len,
+            } => {
+                let mut payload = recording_id.to_le_bytes().to_vec();
+                payload.extend_from_slice(&offset.to_le_bytes());
+                payload.extend_from_slice(&len.to_le_bytes());
+                payload
+            }
+            Command::GetRecordingChecksum { recording_id } => recording_id.to_le_bytes().to_vec(),
+            Command::Raw { payload, .. } => payload,
+            _ => Vec::new(),
+        };
+        Frame {
+            id,
+            sequence,
+            payload,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    pub battery_millivolts: u16,
+    pub recording: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Battery {
+    pub millivolts: u16,
+    /// Estimated charge left, 0 to 100
+    pub percent: u8,
+    pub charging: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Storage {
+    pub used_bytes: u32,
+    pub total_bytes: u32,
+}
+
+/// One recording stored on the monitor
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingInfo {
+    pub id: u16,
+    /// Start of the recording by the clock of the monitor, in seconds since the unix epoch
+    pub start_time: u32,
+    pub duration_secs: u32,
+    pub size_bytes: u32,
+}
+
+/// Part of a recording read with `Command::ReadRecording`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingBlock {
+    pub recording_id: u16,
+    pub offset: u32,
+    pub len: u32,
+    /// CRC-32 of the block as computed by the device
+    pub crc32: u32,
+    /// Block read from the data channel, empty until the device loop has read it
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingChecksum {
+    pub recording_id: u16,
+    pub size_bytes: u32,
+    pub crc32: u32,
+}
+
+/// Responses sent by the monitor
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "camelCase")]
+pub enum Response {
+    Pong,
+    Status(Status),
+    Battery(Battery),
+    Storage(Storage),
+    /// Real-time clock of the device, in milliseconds since the unix epoch
+    #[serde(rename_all = "camelCase")]
+    Time { unix_millis: u64 },
+    Recordings { recordings: Vec<RecordingInfo> },
+    Config(DeviceConfig),
+    Block(RecordingBlock),
+    Checksum(RecordingChecksum),
+    /// The command was carried out and there is nothing to report
+    #[serde(rename_all = "camelCase")]
+    Ack { command_id: u8 },
+    /// The command was refused
+    #[serde(rename_all = "camelCase")]
+    Nack { command_id: u8, code: u8 },
+    /// Any other response, passed on as is
+    Raw { id: u8, payload: Vec<u8> },
+}
+
+impl Response {
+    pub fn from_frame(frame: Frame) -> Result<Self, Error> {
+        let Frame { id, payload, .. } = frame;
+        let malformed = Error::Malformed { id };
+        match id {
+            NACK_ID => match payload[..] {
+                [command_id, code] => Ok(Response::Nack { command_id, code }),
+                _ => Err(malformed),
+            },
+            id if id == CMD_PING | RESPONSE_FLAG => Ok(Response::Pong),
+            id if id == CMD_GET_STATUS | RESPONSE_FLAG => match payload[..] {
+                [mv_lo, mv_hi, recording] => Ok(Response::Status(Status {
+                    battery_millivolts: u16::from_le_bytes([mv_lo, mv_hi]),
+                    recording: recording != 0,
+                })),
+                _ => Err(malformed),
+            },
+            id if id == CMD_GET_BATTERY | RESPONSE_FLAG => match payload[..] {
+                [mv_lo, mv_hi, percent, charging] => Ok(Response::Battery(Battery {
+                    millivolts: u16::from_le_bytes([mv_lo, mv_hi]),
+                    percent,
+                    charging: charging != 0,
+                })),
+                _ => Err(malformed),
+            },
+            id if id == CMD_GET_STORAGE | RESPONSE_FLAG => match payload[..] {
+                [u0, u1, u2, u3, t0, t1, t2, t3] => Ok(Response::Storage(Storage {
+                    used_bytes: u32::from_le_bytes([u0, u1, u2, u3]),
+                    total_bytes: u32::from_le_bytes([t0, t1, t2, t3]),
+                })),
+                _ => Err(malformed),
+            },
+            id if id == CMD_GET_TIME | RESPONSE_FLAG => match payload[..] {
+                [b0, b1, b2, b3, b4, b5, b6, b7] => Ok(Response::Time {
+                    unix_millis: u64::from_le_bytes([b0, b1, b2, b3, b4, b5, b6, b7]),
+                }),
+                _ => Err(malformed),
+            },
+            id if id == CMD_GET_CONFIG | RESPONSE_FLAG => match decode_config(&payload[..]) {
+                Some(config) => Ok(Response::Config(config)),
+                None => Err(malformed),
+            },
+            id if id == CMD_LIST_RECORDINGS | RESPONSE_FLAG => {
+                if payload.len() % RECORDING_INFO_LEN != 0 {
+                    return Err(malformed);
+                }
+                let recordings = payload
+                    .chunks(RECORDING_INFO_LEN)
+                    .map(|entry| RecordingInfo {
+                        id: u16::from_le_bytes([entry[0], entry[1]]),
+                        start_time: u32::from_le_bytes([entry[2], entry[3], entry[4], entry[5]]),
+                        duration_secs: u32::from_le_bytes([entry[6], entry[7], entry[8], entry[9]]),
+                        size_bytes: u32::from_le_bytes([entry[10], entry[11], entry[12], entry[13]]),
+                    })
+                    .collect();
+                Ok(Response::Recordings { recordings })
+            }
+            id if id == CMD_READ_RECORDING | RESPONSE_FLAG => match payload[..] {
+                [r0, r1, o0, o1, o2, o3, l0, l1, l2, l3, c0, c1, c2, c3] => {
+                    Ok(Response::Block(RecordingBlock {
+                        recording_id: u16::from_le_bytes([r0, r1]),
+                        offset: u32::from_le_bytes([o0, o1, o2, o3]),
+                        len: u32::from_le_bytes([l0, l1, l2, l3]),
+                        crc32: u32::from_le_bytes([c0, c1, c2, c3]),
+                        data: Vec::new(),
+                    }))
+                }
+                _ => Err(malformed),
+            },
+            id if id == CMD_GET_RECORDING_CHECKSUM | RESPONSE_FLAG => match payload[..] {
+                [r0, r1, s0, s1, s2, s3, c0, c1, c2, c3] => {
+                    Ok(Response::Checksum(RecordingChecksum {
+                        recording_id: u16::from_le_bytes([r0, r1]),
+                        size_bytes: u32::from_le_bytes([s0, s1, s2, s3]),
+                        crc32: u32::from_le_bytes([c0, c1, c2, c3]),
+                    }))
+                }
+                _ => Err(malformed),
+            },
+            id if id & RESPONSE_FLAG != 0 && payload.is_empty() => Ok(Response::Ack {
+                command_id: id & !RESPONSE_FLAG,
+            }),
+            id => Ok(Response::Raw { id, payload }),
+        }
+    }
+}
+
+// Sample rate, lead set as number of electrodes, gain, recording duration in hours, then the
+// patient id in ASCII with its length in front
+fn encode_config(config: &DeviceConfig) -> Vec<u8> {
+    let patient_id = &config.patient_id.as_bytes()[..usize::min(config.patient_id.len(), 255)];
+    let mut payload = config.sample_rate_hz.to_le_bytes().to_vec();
+    payload.push(config.lead_set.electrodes());
+    payload.push(config.gain);
+    payload.push(config.recording_hours);
+    payload.push(patient_id.len() as u8);
+    payload.extend_from_slice(patient_id);
+    payload
+}
+
+fn decode_config(payload: &[u8]) -> Option<DeviceConfig> {
+    match payload {
+        [r0, r1, leads, gain, hours, id_len, patient_id @ ..] if patient_id.len() == *id_len as usize => {
+            Some(DeviceConfig {
+                sample_rate_hz: u16::from_le_bytes([*r0, *r1]),
+                lead_set: LeadSet::from_electrodes(*leads)?,
+                gain: *gain,
+                recording_hours: *hours,
+                patient_id: String::from_utf8(patient_id.to_vec()).ok()?,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Events the monitor raises on its own
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "notification", rename_all = "camelCase")]
+pub enum Notification {
+    /// One or more electrodes lost contact. Bit n of `leads` is set for lead n.
+    LeadOff { leads: u8 },
+    #[serde(rename_all = "camelCase")]
+    LowBattery { battery_millivolts: u16 },
+    /// The patient pressed the event button
+    EventButton,
+    /// Any other notification, passed on as is
+    Raw { id: u8, payload: Vec<u8> },
+}
+
+impl Notification {
+    pub fn from_frame(frame: Frame) -> Result<Self, Error> {
+        let Frame { id, payload, .. } = frame;
+        let malformed = Error::Malformed { id };
+        match id {
+            NOTIFY_LEAD_OFF => match payload[..] {
+                [leads] => Ok(Notification::LeadOff { leads }),
+                _ => Err(malformed),
+            },
+            NOTIFY_LOW_BATTERY => match payload[..] {
+                [mv_lo, mv_hi] => Ok(Notification::LowBattery {
+                    battery_millivolts: u16::from_le_bytes([mv_lo, mv_hi]),
+                }),
+                _ => Err(malformed),
+            },
+            NOTIFY_EVENT_BUTTON => Ok(Notification::EventButton),
+            id => Ok(Notification::Raw { id, payload }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_roundtrip() {
+        let frame = Frame {
+            id: 0x42,
+            sequence: 7,
+            payload: vec![1, 2, 3],
+        };
+        let buf = frame.encode().unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 3);
+        assert_eq!(frame_len(&buf[..]), Some(buf.len()));
+        assert_eq!(Frame::decode(&buf[..]).unwrap(), frame);
+    }
+
+    #[test]
+    fn decode_rejects_corrupted_frame() {
+        let mut buf = Command::Ping.into_frame(1).encode().unwrap();
+        buf[1] ^= 0x01;
+        assert!(matches!(Frame::decode(&buf[..]), Err(Error::Crc { .. })));
+        assert_eq!(Frame::decode(&buf[..3]), Err(Error::TooShort(3)));
+    }
+
+    #[test]
+    fn typed_responses() {
+        let status = Frame {
+            id: CMD_GET_STATUS | RESPONSE_FLAG,
+            sequence: 0,
+            payload: vec![0x10, 0x0e, 1],
+        };
+        assert_eq!(
+            Response::from_frame(status),
+            Ok(Response::Status(Status {
+                battery_millivolts: 3600,
+                recording: true
+            }))
+        );
+        let ack = Frame {
+            id: CMD_START_RECORDING | RESPONSE_FLAG,
+            sequence: 0,
+            payload: vec![],
+        };
+        assert_eq!(
+            Response::from_frame(ack),
+            Ok(Response::Ack {
+                command_id: CMD_START_RECORDING
+            })
+        );
+    }
+
+    #[test]
+    fn config_roundtrip() {
+        let config = DeviceConfig {
+            sample_rate_hz: 500,
+            lead_set: LeadSet::TwelveLead,
+            gain: 6,
+            recording_hours: 48,
+            patient_id: "P-7".to_string(),
+        };
+        let frame = Command::SetConfig(config.clone()).into_frame(3);
+        assert_eq!(frame.payload.len(), 6 + 3);
+        let res = Frame {
+            id: CMD_GET_CONFIG | RESPONSE_FLAG,
+            sequence: 3,
+            payload: frame.payload,
+        };
+        assert_eq!(Response::from_frame(res), Ok(Response::Config(config)));
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn notifications_are_told_apart() {
+        let lead_off = Frame {
+            id: NOTIFY_LEAD_OFF,
+            sequence: 0,
+            payload: vec![0b101],
+        };
+        assert!(lead_off.is_notification());
+        assert_eq!(
+            Notification::from_frame(lead_off),
+            Ok(Notification::LeadOff { leads: 0b101 })
+        );
+        assert!(!Command::Ping.into_frame(0).is_notification());
+        let nack = Frame {
+            id: NACK_ID,
+            sequence: 0,
+            payload: vec![CMD_PING, 1],
+        };
+        assert!(!nack.is_notification());
+    }
+
+    #[test]
+    fn answers_only_the_command_sent() {
+        let pong = Frame {
+            id: CMD_PING | RESPONSE_FLAG,
+            sequence: 0,
+            payload: vec![],
+        };
+        assert!(pong.answers(CMD_PING));
+        assert!(!pong.answers(CMD_GET_STATUS));
+        assert!(!Command::Ping.into_frame(0).answers(CMD_PING));
+        let nack = Frame {
+            id: NACK_ID,
+            sequence: 0,
+            payload: vec![CMD_ERASE_MEMORY, 1],
+        };
+        assert!(nack.answers(CMD_ERASE_MEMORY));
+        assert!(!nack.answers(CMD_PING));
+    }
+
+    #[test]
+    fn raw_ids_of_responses_and_notifications_are_reserved() {
+        let raw = |id| Command::Raw { id, payload: vec![] };
+        assert_eq!(raw(0x3f).check_id(), Ok(()));
+        assert_eq!(raw(0x41).check_id(), Err(Error::ReservedId(0x41)));
+        assert_eq!(raw(0x81).check_id(), Err(Error::ReservedId(0x81)));
+        assert_eq!(Command::Ping.check_id(), Ok(()));
+    }
+}
@@ -0,0 +1,157 @@
+// Parts of libusb that the libusb crate does not wrap, used through libusb-sys directly
+use futures::channel::mpsc;
+use libusb_sys as ffi;
+use std::io;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::time::Duration;
+
+// USB 3.0 allows at most 7 levels of hubs
+const MAX_PORT_DEPTH: usize = 7;
+
+// Hotplug API, not covered by libusb-sys
+const LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED: c_int = 0x01;
+const LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT: c_int = 0x02;
+const LIBUSB_HOTPLUG_NO_FLAGS: c_int = 0;
+const LIBUSB_HOTPLUG_MATCH_ANY: c_int = -1;
+
+type HotplugCallbackFn = extern "C" fn(
+    context: *mut ffi::libusb_context,
+    device: *mut ffi::libusb_device,
+    event: c_int,
+    user_data: *mut c_void,
+) -> c_int;
+
+extern "C" {
+    fn libusb_hotplug_register_callback(
+        context: *mut ffi::libusb_context,
+        events: c_int,
+        flags: c_int,
+        vendor_id: c_int,
+        product_id: c_int,
+        dev_class: c_int,
+        cb_fn: HotplugCallbackFn,
+        user_data: *mut c_void,
+        callback_handle: *mut c_int,
+    ) -> c_int;
+}
+
+// Runs on the event thread of `RawContext::watch_hotplug`, which is the only user of the Sender
+extern "C" fn hotplug_callback(
+    _context: *mut ffi::libusb_context,
+    _device: *mut ffi::libusb_device,
+    event: c_int,
+    user_data: *mut c_void,
+) -> c_int {
+    let notify_tx = unsafe { &mut *(user_data as *mut mpsc::Sender<()>) };
+    debug!("Hotplug event {}", event);
+    // A full channel means a refresh is already pending
+    let _ = notify_tx.try_send(());
+    // Returning 0 keeps the callback registered
+    0
+}
+
+/// A libusb context owned next to the `libusb::Context`, for calls that need raw pointers.
+pub struct RawContext {
+    context: *mut ffi::libusb_context,
+}
+
+unsafe impl Sync for RawContext {}
+unsafe impl Send for RawContext {}
+
+impl Drop for RawContext {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::libusb_exit(self.context);
+        }
+    }
+}
+
+impl RawContext {
+    pub fn new() -> Result<Self, io::Error> {
+        let mut context = ptr::null_mut();
+        let res = unsafe { ffi::libusb_init(&mut context) };
+        if res != 0 {
+            return Err(io::Error::other(format!("libusb_init failed: {}", res)));
+        }
+        Ok(RawContext { context })
+    }
+
+    /// Returns the chain of hub ports leading to the device at `bus_number`:`address`, or None if
+    /// there is no such device.
+    pub fn port_chain(&self, bus_number: u8, address: u8) -> Option<Vec<u8>> {
+        let mut list: *const *mut ffi::libusb_device = ptr::null();
+        let n = unsafe { ffi::libusb_get_device_list(self.context, &mut list) };
+        if n < 0 {
+            error!("libusb_get_device_list failed: {}", n);
+            return None;
+        }
+        let mut chain = None;
+        for i in 0..n as usize {
+            unsafe {
+                let device = *list.add(i);
+                if ffi::libusb_get_bus_number(device) != bus_number
+                    || ffi::libusb_get_device_address(device) != address
+                {
+                    continue;
+                }
+                let mut ports = [0u8; MAX_PORT_DEPTH];
+                let len = ffi::libusb_get_port_numbers(
+                    device,
+                    ports.as_mut_ptr(),
+                    ports.len() as i32,
+                );
+                if len >= 0 {
+                    chain = Some(ports[..len as usize].to_vec());
+                }
+                break;
+            }
+        }
+        unsafe { ffi::libusb_free_device_list(list, 1) };
+        chain
+    }
+
+    /// Sends a notification on `notify_tx` every time a device with the given VID/PID arrives or
+    /// leaves. Fails if the platform has no hotplug support, in which case the caller has to
+    /// poll.
+    pub fn watch_hotplug(
+        &'static self,
+        vendor_id: u16,
+        product_id: u16,
+        notify_tx: mpsc::Sender<()>,
+    ) -> Result<(), io::Error> {
+        if unsafe { ffi::libusb_has_capability(ffi::LIBUSB_CAP_HAS_HOTPLUG) } == 0 {
+            return Err(io::Error::other("libusb has no hotplug support on this platform"));
+        }
+        // Owned by the callback from here on. It stays registered for the lifetime of the
+        // context, so it is never freed.
+        let user_data = Box::into_raw(Box::new(notify_tx)) as *mut c_void;
+        let mut handle = 0;
+        let res = unsafe {
+            libusb_hotplug_register_callback(
+                self.context,
+                LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED | LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT,
+                LIBUSB_HOTPLUG_NO_FLAGS,
+                vendor_id.into(),
+                product_id.into(),
+                LIBUSB_HOTPLUG_MATCH_ANY,
+                hotplug_callback,
+                user_data,
+                &mut handle,
+            )
+        };
+        if res != 0 {
+            drop(unsafe { Box::from_raw(user_data as *mut mpsc::Sender<()>) });
+            return Err(io::Error::other(format!("libusb_hotplug_register_callback failed: {}", res)));
+        }
+        // Callbacks are only invoked while someone handles events on the context
+        std::thread::spawn(move || loop {
+            let res = unsafe { ffi::libusb_handle_events(self.context) };
+            if res != 0 && res != ffi::LIBUSB_ERROR_INTERRUPTED {
+                error!("libusb_handle_events failed: {}", res);
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        });
+        Ok(())
+    }
+}
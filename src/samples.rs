@@ -0,0 +1,317 @@
+//! Decoding of the ECG samples streamed on the visualization endpoint (EP_VIS).
+//!
+//! Every packet starts with a 4 byte header followed by interleaved samples, one frame of all
+//! channels after the other:
+//!
+//! | offset | size | field                                  |
+//! |--------|------|----------------------------------------|
+//! | 0      | 2    | packet counter, little endian, wraps   |
+//! | 2      | 1    | status flags, see `StatusFlags`        |
+//! | 3      | 1    | reserved                               |
+//! | 4      |      | samples, signed little endian          |
+//!
+//! The number of channels and the sample format are not in the packet, they depend on the
+//! firmware, see `PacketLayout::for_firmware`.
+use crate::deviceinfo::FirmwareVersion;
+use crate::usbfutures::VisFrame;
+use byteorder::{ByteOrder, LittleEndian};
+use serde::Serialize;
+use std::time::SystemTime;
+use thiserror::Error;
+
+const PACKET_HEADER_LEN: usize = 4;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("no known sample layout for firmware {0}")]
+    UnsupportedFirmware(FirmwareVersion),
+    #[error("sample packet of {actual} bytes, expected {expected}")]
+    Length { expected: usize, actual: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    I16Le,
+    I24Le,
+}
+
+impl SampleFormat {
+    pub fn bytes(self) -> usize {
+        match self {
+            SampleFormat::I16Le => 2,
+            SampleFormat::I24Le => 3,
+        }
+    }
+
+    fn read(self, buf: &[u8]) -> i32 {
+        match self {
+            SampleFormat::I16Le => LittleEndian::read_i16(buf) as i32,
+            SampleFormat::I24Le => LittleEndian::read_i24(buf),
+        }
+    }
+}
+
+/// How the samples are laid out in one packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketLayout {
+    pub channels: usize,
+    pub format: SampleFormat,
+    /// Samples per channel in one packet
+    pub frames: usize,
+}
+
+impl PacketLayout {
+    /// Layout used by the given firmware. Firmware 1.x streams 3 channels of 16 bit samples,
+    /// firmware 2.x 4 channels of 24 bit samples. Other major versions are unknown, their packets
+    /// can't be decoded.
+    pub fn for_firmware(firmware: FirmwareVersion) -> Option<Self> {
+        match firmware.major {
+            1 => Some(PacketLayout {
+                channels: 3,
+                format: SampleFormat::I16Le,
+                frames: 10,
+            }),
+            2 => Some(PacketLayout {
+                channels: 4,
+                format: SampleFormat::I24Le,
+                frames: 5,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn packet_len(&self) -> usize {
+        PACKET_HEADER_LEN + self.frames * self.channels * self.format.bytes()
+    }
+}
+
+/// Status bits in the header of a sample packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StatusFlags(pub u8);
+
+impl StatusFlags {
+    /// At least one electrode has no contact
+    pub const LEAD_OFF: u8 = 0x01;
+    pub const LOW_BATTERY: u8 = 0x02;
+    /// The front end feeds its calibration signal instead of the electrodes
+    pub const CALIBRATION: u8 = 0x04;
+    pub const EVENT_BUTTON: u8 = 0x08;
+    /// The ADC clipped at least one sample
+    pub const SATURATED: u8 = 0x10;
+
+    pub fn contains(self, flag: u8) -> bool {
+        self.0 & flag == flag
+    }
+}
+
+/// Unit of the samples in a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SampleUnit {
+    AdcCounts,
+    Microvolts,
+}
+
+/// Samples of all channels from one packet
+#[derive(Debug, PartialEq)]
+pub struct SampleBlock {
+    pub counter: u16,
+    pub flags: StatusFlags,
+    /// Time the packet arrived at the host
+    pub timestamp: SystemTime,
+    pub channels: usize,
+    /// ADC counts as decoded, microvolts once calibrated, see `Calibration::apply`
+    pub unit: SampleUnit,
+    /// One frame of all channels after the other
+    pub samples: Vec<f32>,
+    /// Set for each sample that was not measured but interpolated over lost packets
+    pub synthetic: Vec<bool>,
+}
+
+// `clone_from` keeps the buffers of the block it overwrites
+impl Clone for SampleBlock {
+    fn clone(&self) -> Self {
+        SampleBlock {
+            counter: self.counter,
+            flags: self.flags,
+            timestamp: self.timestamp,
+            channels: self.channels,
+            unit: self.unit,
+            samples: self.samples.clone(),
+            synthetic: self.synthetic.clone(),
+        }
+    }
+
+    fn clone_from(&mut self, source: &Self) {
+        self.counter = source.counter;
+        self.flags = source.flags;
+        self.timestamp = source.timestamp;
+        self.channels = source.channels;
+        self.unit = source.unit;
+        self.samples.clone_from(&source.samples);
+        self.synthetic.clone_from(&source.synthetic);
+    }
+}
+
+impl SampleBlock {
+    /// Number of samples per channel
+    pub fn frames(&self) -> usize {
+        self.samples.len().checked_div(self.channels).unwrap_or(0)
+    }
+
+    /// Samples of one channel
+    pub fn channel(&self, channel: usize) -> impl Iterator<Item = f32> + '_ {
+        self.samples.iter().skip(channel).step_by(self.channels.max(1)).copied()
+    }
+}
+
+/// Turns visualization packets into sample blocks
+#[derive(Debug, Clone)]
+pub struct SampleDecoder {
+    layout: PacketLayout,
+}
+
+impl SampleDecoder {
+    pub fn new(firmware: FirmwareVersion) -> Result<Self, DecodeError> {
+        match PacketLayout::for_firmware(firmware) {
+            Some(layout) => Ok(SampleDecoder::with_layout(layout)),
+            None => Err(DecodeError::UnsupportedFirmware(firmware)),
+        }
+    }
+
+    pub fn with_layout(layout: PacketLayout) -> Self {
+        SampleDecoder { layout }
+    }
+
+    pub fn layout(&self) -> PacketLayout {
+        self.layout
+    }
+
+    pub fn decode(&self, frame: &VisFrame) -> Result<SampleBlock, DecodeError> {
+        let data = &frame.data[..];
+        let expected = self.layout.packet_len();
+        if data.len() != expected {
+            return Err(DecodeError::Length {
+                expected,
+                actual: data.len(),
+            });
+        }
+        let format = self.layout.format;
+        // 24 bit counts are exact in an f32
+        let samples: Vec<f32> = data[PACKET_HEADER_LEN..]
+            .chunks(format.bytes())
+            .map(|sample| format.read(sample) as f32)
+            .collect();
+        Ok(SampleBlock {
+            counter: LittleEndian::read_u16(&data[..2]),
+            flags: StatusFlags(data[2]),
+            timestamp: frame.timestamp,
+            channels: self.layout.channels,
+            unit: SampleUnit::AdcCounts,
+            synthetic: vec![false; samples.len()],
+            samples,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn firmware(major: u8, minor: u8) -> FirmwareVersion {
+        FirmwareVersion {
+            major,
+            minor,
+            sub_minor: 0,
+        }
+    }
+
+    fn captured(hex: &str) -> VisFrame {
+        let hex: String = hex.split_whitespace().collect();
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        VisFrame {
+            timestamp: SystemTime::UNIX_EPOCH,
+            data,
+        }
+    }
+
+    #[test]
+    fn firmware_1_three_channels_16_bit() {
+        let frame = captured(
+            "02 01 00 00
+             10 00 f0 ff 00 01  12 00 ee ff 04 01  14 00 ec ff 08 01  16 00 ea ff 0c 01
+             18 00 e8 ff 10 01  1a 00 e6 ff 14 01  1c 00 e4 ff 18 01  1e 00 e2 ff 1c 01
+             20 00 e0 ff 20 01  22 00 de ff 24 01",
+        );
+        let decoder = SampleDecoder::new(firmware(1, 4)).unwrap();
+        let block = decoder.decode(&frame).unwrap();
+        assert_eq!(block.counter, 0x0102);
+        assert_eq!(block.flags, StatusFlags(0));
+        assert_eq!(block.frames(), 10);
+        assert_eq!(block.unit, SampleUnit::AdcCounts);
+        let channel = |ch: usize| block.channel(ch).map(|s| s as i32).collect::<Vec<_>>();
+        assert_eq!(channel(0), (0..10).map(|i| 16 + 2 * i).collect::<Vec<_>>());
+        assert_eq!(channel(1), (0..10).map(|i| -16 - 2 * i).collect::<Vec<_>>());
+        assert_eq!(channel(2), (0..10).map(|i| 256 + 4 * i).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn firmware_2_four_channels_24_bit() {
+        let frame = captured(
+            "34 12 05 00
+             e8 03 00 18 fc ff ff ff 7f 00 00 80
+             4c 04 00 b4 fb ff ff ff 7f 00 00 80
+             b0 04 00 50 fb ff ff ff 7f 00 00 80
+             14 05 00 ec fa ff ff ff 7f 00 00 80
+             78 05 00 88 fa ff ff ff 7f 00 00 80",
+        );
+        let decoder = SampleDecoder::new(firmware(2, 1)).unwrap();
+        let block = decoder.decode(&frame).unwrap();
+        assert_eq!(block.counter, 0x1234);
+        assert!(block.flags.contains(StatusFlags::LEAD_OFF));
+        assert!(block.flags.contains(StatusFlags::CALIBRATION));
+        assert!(!block.flags.contains(StatusFlags::LOW_BATTERY));
+        assert_eq!(block.channels, 4);
+        let channel = |ch: usize| block.channel(ch).map(|s| s as i32).collect::<Vec<_>>();
+        assert_eq!(channel(0), vec![1000, 1100, 1200, 1300, 1400]);
+        assert_eq!(channel(1), vec![-1000, -1100, -1200, -1300, -1400]);
+        assert!(channel(2).iter().all(|&s| s == 0x7f_ffff));
+        assert!(channel(3).iter().all(|&s| s == -0x80_0000));
+    }
+
+    #[test]
+    fn clone_from_keeps_the_buffers() {
+        let decoder = SampleDecoder::new(firmware(1, 0)).unwrap();
+        let first = decoder.decode(&captured(&"00".repeat(64))).unwrap();
+        let second = decoder.decode(&captured(&format!("0100 0000 0700 {}", "00".repeat(58)))).unwrap();
+
+        let mut kept = first.clone();
+        let buffer = kept.samples.as_ptr();
+        kept.clone_from(&second);
+        assert_eq!(kept, second);
+        assert_eq!(kept.samples.as_ptr(), buffer);
+    }
+
+    #[test]
+    fn rejects_unknown_layouts() {
+        assert_eq!(
+            SampleDecoder::new(firmware(0, 9)).unwrap_err(),
+            DecodeError::UnsupportedFirmware(firmware(0, 9))
+        );
+        assert_eq!(
+            SampleDecoder::new(firmware(3, 0)).unwrap_err(),
+            DecodeError::UnsupportedFirmware(firmware(3, 0))
+        );
+        let decoder = SampleDecoder::new(firmware(1, 0)).unwrap();
+        assert_eq!(
+            decoder.decode(&captured("02 01 00 00 10 00")),
+            Err(DecodeError::Length {
+                expected: 64,
+                actual: 6
+            })
+        );
+    }
+}
@@ -0,0 +1,497 @@
+//! Live ECG stream of an acquired monitor.
+//!
+//! The device loop decodes every visualization packet and hands the sample blocks to all
+//! subscribers. Packets lost on the way, on the bus or because a subscriber fell behind, show up
+//! as jumps in the packet counter. A `SampleSubscription` turns these into explicit `Gap` items
+//! and can fill short gaps with interpolated samples that are flagged as synthetic.
+use crate::calibration::{ActiveCalibration, Calibration, CalibrationSource};
+use crate::config::DeviceConfig;
+use crate::filter::FilterChain;
+use crate::samples::{SampleBlock, SampleDecoder};
+use crate::usbfutures::VisFrame;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Sample blocks kept for subscribers that fall behind. At 500 Hz and 5 frames per packet this
+/// is about a second of signal.
+pub const SAMPLE_QUEUE_LEN: usize = 128;
+/// Longest gap a subscription conceals, in packets. At 500 Hz this is a quarter of a second.
+pub const MAX_CONCEAL_PACKETS: u16 = 25;
+// A counter jump of more than this is taken for a restart of the stream, not for lost packets
+const MAX_GAP: u16 = 0x8000;
+
+/// Packets missing between two blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Gap {
+    /// Counter of the last packet before the gap
+    pub after_counter: u16,
+    pub lost_packets: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamItem {
+    Block(SampleBlock),
+    Gap(Gap),
+}
+
+/// Statistics of the live stream of one device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamStats {
+    pub received_packets: u64,
+    pub lost_packets: u64,
+    pub gaps: u64,
+    /// Packets that could not be decoded
+    pub invalid_packets: u64,
+}
+
+/// Counters behind `StreamStats`, shared between the device loop and the registry
+#[derive(Debug, Default)]
+pub struct StreamCounters {
+    received_packets: AtomicU64,
+    lost_packets: AtomicU64,
+    gaps: AtomicU64,
+    invalid_packets: AtomicU64,
+}
+
+impl StreamCounters {
+    pub fn stats(&self) -> StreamStats {
+        StreamStats {
+            received_packets: self.received_packets.load(Ordering::Relaxed),
+            lost_packets: self.lost_packets.load(Ordering::Relaxed),
+            gaps: self.gaps.load(Ordering::Relaxed),
+            invalid_packets: self.invalid_packets.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What the packet counter says about the next block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Continuity {
+    /// First block, or the one right after the previous block
+    InOrder,
+    Gap(Gap),
+    /// Same counter as the previous block
+    Duplicate,
+    /// The counter went back or jumped too far. The stream starts over from this block.
+    Restart,
+}
+
+/// Follows the packet counter of a stream
+#[derive(Debug, Clone, Default)]
+pub struct GapTracker {
+    last: Option<u16>,
+}
+
+impl GapTracker {
+    pub fn track(&mut self, counter: u16) -> Continuity {
+        let last = match self.last.replace(counter) {
+            Some(last) => last,
+            None => return Continuity::InOrder,
+        };
+        match counter.wrapping_sub(last) {
+            0 => Continuity::Duplicate,
+            1 => Continuity::InOrder,
+            jump if jump > MAX_GAP => Continuity::Restart,
+            jump => Continuity::Gap(Gap {
+                after_counter: last,
+                lost_packets: jump - 1,
+            }),
+        }
+    }
+}
+
+// Decodes the visualization packets in the device loop, converts them to microvolts and passes
+// them on to the subscribers
+pub(crate) struct LiveStream {
+    decoder: SampleDecoder,
+    tracker: GapTracker,
+    // None until the config of the device is known, samples stay in ADC counts until then
+    calibration: ActiveCalibration,
+    counters: Arc<StreamCounters>,
+    samples_tx: broadcast::Sender<SampleBlock>,
+}
+
+impl LiveStream {
+    pub(crate) fn new(
+        decoder: SampleDecoder,
+        calibration: ActiveCalibration,
+        counters: Arc<StreamCounters>,
+        samples_tx: broadcast::Sender<SampleBlock>,
+    ) -> Self {
+        let channels = decoder.layout().channels;
+        if let Some(calibrated) = calibration.get().map(|calibration| calibration.channels.len()) {
+            if calibrated != channels {
+                warn!("Calibration for {} channels ignored, the device streams {}", calibrated, channels);
+                calibration.set(None);
+            }
+        }
+        LiveStream {
+            decoder,
+            tracker: GapTracker::default(),
+            calibration,
+            counters,
+            samples_tx,
+        }
+    }
+
+    // Whether the calibration depends on the config of the device
+    pub(crate) fn needs_config(&self) -> bool {
+        match self.calibration.get() {
+            Some(calibration) => calibration.source == CalibrationSource::Nominal,
+            None => true,
+        }
+    }
+
+    // Follows the gain of the device when there is no better calibration than the nominal one
+    pub(crate) fn configure(&mut self, config: &DeviceConfig) {
+        if self.needs_config() {
+            let nominal = Calibration::nominal(self.decoder.layout(), config.gain);
+            self.calibration.set(Some(nominal));
+        }
+    }
+
+    pub(crate) fn push(&mut self, frame: VisFrame) {
+        let mut block = match self.decoder.decode(&frame) {
+            Ok(block) => block,
+            Err(e) => {
+                debug!("Visualization packet ignored: {}", e);
+                self.counters.invalid_packets.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        self.counters.received_packets.fetch_add(1, Ordering::Relaxed);
+        match self.tracker.track(block.counter) {
+            Continuity::InOrder => (),
+            Continuity::Gap(gap) => {
+                warn!("Lost {} visualization packets after {}", gap.lost_packets, gap.after_counter);
+                self.counters.lost_packets.fetch_add(gap.lost_packets as u64, Ordering::Relaxed);
+                self.counters.gaps.fetch_add(1, Ordering::Relaxed);
+            }
+            Continuity::Duplicate => return,
+            Continuity::Restart => info!("Visualization stream restarted at {}", block.counter),
+        }
+        self.calibration.apply(&mut block);
+        // Fails only when nobody is subscribed
+        let _ = self.samples_tx.send(block);
+    }
+}
+
+/// One consumer of the live stream of a device, see `USBDevices::subscribe_samples`
+pub struct SampleSubscription {
+    rx: broadcast::Receiver<SampleBlock>,
+    tracker: GapTracker,
+    // Longest gap filled with interpolated samples, in packets
+    conceal_packets: u16,
+    // Last block as it came, only kept while gaps are concealed
+    previous: Option<SampleBlock>,
+    filter: Option<FilterChain>,
+    queue: VecDeque<StreamItem>,
+}
+
+impl SampleSubscription {
+    pub fn new(rx: broadcast::Receiver<SampleBlock>) -> Self {
+        SampleSubscription {
+            rx,
+            tracker: GapTracker::default(),
+            conceal_packets: 0,
+            previous: None,
+            filter: None,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Fills gaps of up to `packets` packets with samples interpolated linearly between the
+    /// blocks around the gap. The gap is still reported before the synthetic blocks. `packets` is
+    /// capped at `MAX_CONCEAL_PACKETS`.
+    pub fn conceal(&mut self, packets: u16) {
+        self.conceal_packets = packets.min(MAX_CONCEAL_PACKETS);
+        if packets == 0 {
+            self.previous = None;
+        }
+    }
+
+    /// Runs the blocks through `chain` before they are handed out, synthetic ones included, or
+    /// stops filtering. The filters start over after every gap, concealed or not.
+    pub fn filter(&mut self, chain: Option<FilterChain>) {
+        self.filter = chain;
+    }
+
+    /// Waits for the next block or gap. Returns None once the device is gone from the registry.
+    pub async fn next(&mut self) -> Option<StreamItem> {
+        loop {
+            if let Some(item) = self.queue.pop_front() {
+                return Some(item);
+            }
+            let block = match self.rx.recv().await {
+                Ok(block) => block,
+                // The counter shows the blocks that were missed
+                Err(broadcast::RecvError::Lagged(missed)) => {
+                    warn!("Sample subscriber lagged, {} blocks missed", missed);
+                    continue;
+                }
+                Err(broadcast::RecvError::Closed) => return None,
+            };
+            self.push(block);
+        }
+    }
+
+    // Only called once the queue is empty
+    fn push(&mut self, block: SampleBlock) {
+        match self.tracker.track(block.counter) {
+            Continuity::InOrder => (),
+            Continuity::Restart => {
+                if let Some(chain) = &mut self.filter {
+                    chain.reset();
+                }
+            }
+            Continuity::Duplicate => return,
+            Continuity::Gap(gap) => {
+                self.queue.push_back(StreamItem::Gap(gap));
+                if gap.lost_packets <= self.conceal_packets {
+                    if let Some(previous) = &self.previous {
+                        let synthetic = interpolate(previous, &block, gap.lost_packets);
+                        self.queue.extend(synthetic.into_iter().map(StreamItem::Block));
+                    }
+                }
+            }
+        }
+        // Samples are interpolated from the blocks as they came
+        if self.conceal_packets > 0 {
+            match &mut self.previous {
+                Some(previous) => previous.clone_from(&block),
+                None => self.previous = Some(block.clone()),
+            }
+        }
+        self.queue.push_back(StreamItem::Block(block));
+        if let Some(chain) = &mut self.filter {
+            for item in self.queue.iter_mut() {
+                match item {
+                    StreamItem::Gap(_) => chain.reset(),
+                    StreamItem::Block(block) => chain.process(block),
+                }
+            }
+        }
+    }
+}
+
+// Blocks for `lost` packets between `previous` and `next`, each channel running in a straight
+// line from the last sample before the gap to the first one after it
+fn interpolate(previous: &SampleBlock, next: &SampleBlock, lost: u16) -> Vec<SampleBlock> {
+    let channels = previous.channels;
+    let frames = previous.frames();
+    if channels == 0
+        || frames == 0
+        || next.channels != channels
+        || next.frames() != frames
+        || next.unit != previous.unit
+    {
+        return Vec::new();
+    }
+    let last = &previous.samples[(frames - 1) * channels..];
+    let first = &next.samples[..channels];
+    let steps = lost as usize * frames + 1;
+    let elapsed = next.timestamp.duration_since(previous.timestamp).unwrap_or_default();
+    (0..lost as usize)
+        .map(|packet| {
+            let samples = (0..frames * channels)
+                .map(|i| {
+                    let (frame, channel) = (i / channels, i % channels);
+                    let step = packet * frames + frame + 1;
+                    let (a, b) = (last[channel], first[channel]);
+                    a + (b - a) * step as f32 / steps as f32
+                })
+                .collect::<Vec<_>>();
+            SampleBlock {
+                counter: previous.counter.wrapping_add(packet as u16 + 1),
+                flags: Default::default(),
+                timestamp: previous.timestamp + elapsed * (packet as u32 + 1) / (lost as u32 + 1),
+                channels,
+                unit: previous.unit,
+                synthetic: vec![true; samples.len()],
+                samples,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samples::{SampleUnit, StatusFlags};
+    use std::time::{Duration, SystemTime};
+
+    fn block(counter: u16, samples: Vec<f32>) -> SampleBlock {
+        SampleBlock {
+            counter,
+            flags: StatusFlags::default(),
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(counter as u64 * 10),
+            channels: 2,
+            unit: SampleUnit::Microvolts,
+            synthetic: vec![false; samples.len()],
+            samples,
+        }
+    }
+
+    #[test]
+    fn counter_wraps_around() {
+        let mut tracker = GapTracker::default();
+        assert_eq!(tracker.track(0xfffe), Continuity::InOrder);
+        assert_eq!(tracker.track(0xffff), Continuity::InOrder);
+        assert_eq!(tracker.track(0), Continuity::InOrder);
+        assert_eq!(
+            tracker.track(3),
+            Continuity::Gap(Gap {
+                after_counter: 0,
+                lost_packets: 2
+            })
+        );
+        assert_eq!(tracker.track(3), Continuity::Duplicate);
+        assert_eq!(tracker.track(1), Continuity::Restart);
+    }
+
+    #[tokio::test]
+    async fn gaps_are_marked_and_concealed() {
+        let (samples_tx, samples_rx) = broadcast::channel(SAMPLE_QUEUE_LEN);
+        let mut subscription = SampleSubscription::new(samples_rx);
+        subscription.conceal(1);
+        // Channel 0 rises by 10 per frame, channel 1 stays put
+        samples_tx.send(block(1, vec![0.0, 7.0, 10.0, 7.0])).unwrap();
+        samples_tx.send(block(3, vec![40.0, 7.0, 50.0, 7.0])).unwrap();
+        samples_tx.send(block(6, vec![0.0; 4])).unwrap();
+        drop(samples_tx);
+
+        let mut items = Vec::new();
+        while let Some(item) = subscription.next().await {
+            items.push(item);
+        }
+        assert_eq!(items.len(), 6);
+        assert_eq!(
+            items[1],
+            StreamItem::Gap(Gap {
+                after_counter: 1,
+                lost_packets: 1
+            })
+        );
+        match &items[2] {
+            StreamItem::Block(synthetic) => {
+                assert_eq!(synthetic.counter, 2);
+                assert_eq!(synthetic.samples, vec![20.0, 7.0, 30.0, 7.0]);
+                assert!(synthetic.synthetic.iter().all(|&s| s));
+            }
+            item => panic!("expected a synthetic block, got {:?}", item),
+        }
+        // Two lost packets are more than the subscription conceals
+        assert_eq!(
+            items[4],
+            StreamItem::Gap(Gap {
+                after_counter: 3,
+                lost_packets: 2
+            })
+        );
+        assert!(matches!(&items[5], StreamItem::Block(b) if b.counter == 6 && !b.synthetic[0]));
+    }
+
+    #[tokio::test]
+    async fn long_gaps_are_not_concealed() {
+        let (samples_tx, samples_rx) = broadcast::channel(SAMPLE_QUEUE_LEN);
+        let mut subscription = SampleSubscription::new(samples_rx);
+        subscription.conceal(u16::MAX);
+        samples_tx.send(block(1, vec![0.0; 4])).unwrap();
+        samples_tx.send(block(MAX_CONCEAL_PACKETS + 3, vec![0.0; 4])).unwrap();
+        drop(samples_tx);
+
+        let mut items = Vec::new();
+        while let Some(item) = subscription.next().await {
+            items.push(item);
+        }
+        assert_eq!(items.len(), 3);
+        assert!(matches!(items[1], StreamItem::Gap(gap) if gap.lost_packets == MAX_CONCEAL_PACKETS + 1));
+    }
+
+    #[tokio::test]
+    async fn filters_start_over_after_gaps() {
+        use crate::filter::FilterSpec;
+
+        let (samples_tx, samples_rx) = broadcast::channel(SAMPLE_QUEUE_LEN);
+        let mut subscription = SampleSubscription::new(samples_rx);
+        let chain = FilterChain::new(&[FilterSpec::Highpass { cutoff_hz: 0.5 }], 250).unwrap();
+        subscription.filter(Some(chain));
+        // The offset of the electrodes jumps while packets are lost
+        samples_tx.send(block(1, vec![1000.0; 4])).unwrap();
+        samples_tx.send(block(2, vec![1000.0; 4])).unwrap();
+        samples_tx.send(block(4, vec![5000.0; 4])).unwrap();
+        drop(samples_tx);
+
+        let mut items = Vec::new();
+        while let Some(item) = subscription.next().await {
+            items.push(item);
+        }
+        assert_eq!(items.len(), 4);
+        assert!(matches!(items[2], StreamItem::Gap(_)));
+        for item in &items {
+            if let StreamItem::Block(block) = item {
+                assert!(block.samples.iter().all(|s| s.abs() < 0.01), "{:?}", block);
+            }
+        }
+    }
+
+    #[test]
+    fn nominal_calibration_follows_the_config() {
+        use crate::calibration::ChannelCalibration;
+        use crate::config::LeadSet;
+        use crate::samples::{PacketLayout, SampleFormat};
+        use crate::usbfutures::VisFrame;
+
+        let layout = PacketLayout {
+            channels: 2,
+            format: SampleFormat::I16Le,
+            frames: 1,
+        };
+        let frame = |counter: u16| VisFrame {
+            timestamp: SystemTime::UNIX_EPOCH,
+            data: vec![counter as u8, 0, 0, 0, 0x00, 0x10, 0x00, 0xf0],
+        };
+        let mut config = DeviceConfig {
+            sample_rate_hz: 250,
+            lead_set: LeadSet::ThreeLead,
+            gain: 8,
+            recording_hours: 24,
+            patient_id: "P-1".to_string(),
+        };
+        let (tx, mut rx) = broadcast::channel(SAMPLE_QUEUE_LEN);
+        let counters = Arc::new(StreamCounters::default());
+        let decoder = SampleDecoder::with_layout(layout);
+        let calibration = ActiveCalibration::default();
+        let mut live = LiveStream::new(decoder.clone(), calibration, Arc::clone(&counters), tx.clone());
+        live.push(frame(1));
+        assert_eq!(rx.try_recv().unwrap().unit, SampleUnit::AdcCounts);
+        live.configure(&config);
+        live.push(frame(2));
+        // 4096 counts of 2.4 V over 15 bits at a gain of 8
+        assert_eq!(rx.try_recv().unwrap().samples, vec![37_500.0, -37_500.0]);
+
+        let measured = Calibration {
+            source: CalibrationSource::Measured,
+            channels: vec![
+                ChannelCalibration {
+                    gain_microvolts_per_count: 1.0,
+                    offset_counts: 96.0,
+                };
+                2
+            ],
+        };
+        let calibration = ActiveCalibration::default();
+        calibration.set(Some(measured));
+        let mut live = LiveStream::new(decoder, calibration, counters, tx);
+        assert!(!live.needs_config());
+        config.gain = 1;
+        live.configure(&config);
+        live.push(frame(3));
+        assert_eq!(rx.try_recv().unwrap().samples, vec![4000.0, -4192.0]);
+    }
+}
@@ -0,0 +1,311 @@
+use crate::usb::{COMMAND_PACKET_SIZE, MAX_MESSAGE_LEN};
+use crate::usbfutures::{DataChannel, Device, VisFrame, VisProxy};
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The channels the bridge needs from a connected monitor. `Device` implements it on top of
+/// libusb, `Loopback` implements it in memory so that the bridge logic can run without hardware.
+pub trait Transport: Send + 'static {
+    type Command: AsyncRead + AsyncWrite + Unpin + Send;
+    type Vis: Stream<Item = VisFrame> + Unpin + Send;
+    type Data: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Command channel (EP_OUT/EP_IN)
+    fn command(&mut self) -> &mut Self::Command;
+    /// Takes the visualization stream (EP_VIS), so that it can be read next to the command
+    /// channel. Later calls get a stream that has ended.
+    fn take_vis(&mut self) -> Self::Vis;
+    /// Takes the bulk data channel (EP_DATA_OUT/EP_DATA_IN), so that it can be read apart from
+    /// the command channel. Later calls get a channel that fails.
+    fn take_data(&mut self) -> Self::Data;
+    /// Tears down the transport. Returns once all resources are released.
+    fn close(self);
+}
+
+/// Reads one message from the command channel. Packets are collected until a short packet, which
+/// may be a zero length packet, or until the message is as long as `expected_len` says. It is
+/// given the bytes read so far and returns the full length once the protocol can tell.
+pub async fn read_message<R, F>(reader: &mut R, expected_len: F) -> Result<Vec<u8>, io::Error>
+where
+    R: AsyncRead + Unpin,
+    F: Fn(&[u8]) -> Option<usize>,
+{
+    MessageReader::default().read(reader, expected_len).await
+}
+
+/// Like `read_message`, but keeps the packets of a partly read message when the future returned
+/// by `read` is dropped, so that reading can be raced against other futures.
+#[derive(Default)]
+pub struct MessageReader {
+    msg: Vec<u8>,
+    // Set while the rest of a message longer than MAX_MESSAGE_LEN is dropped
+    discarding: bool,
+}
+
+impl MessageReader {
+    pub async fn read<R, F>(&mut self, reader: &mut R, expected_len: F) -> Result<Vec<u8>, io::Error>
+    where
+        R: AsyncRead + Unpin,
+        F: Fn(&[u8]) -> Option<usize>,
+    {
+        let mut packet = [0u8; COMMAND_PACKET_SIZE];
+        loop {
+            let len = reader.read(&mut packet[..]).await?;
+            // A message that is too long fails once its last packet is in, so that the next
+            // message starts at its first packet
+            if self.discarding {
+                if len < COMMAND_PACKET_SIZE {
+                    self.discarding = false;
+                    return Err(message_too_long());
+                }
+                continue;
+            }
+            // A zero length packet ending the previous message, which was already complete by
+            // its length
+            if len == 0 && self.msg.is_empty() {
+                continue;
+            }
+            self.msg.extend_from_slice(&packet[..len]);
+            if self.msg.len() > MAX_MESSAGE_LEN {
+                self.msg.clear();
+                if len < COMMAND_PACKET_SIZE {
+                    return Err(message_too_long());
+                }
+                self.discarding = true;
+                continue;
+            }
+            if len < COMMAND_PACKET_SIZE
+                || matches!(expected_len(&self.msg), Some(expected) if self.msg.len() >= expected)
+            {
+                return Ok(std::mem::take(&mut self.msg));
+            }
+        }
+    }
+}
+
+fn message_too_long() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("message longer than {} bytes", MAX_MESSAGE_LEN),
+    )
+}
+
+impl Transport for Device {
+    type Command = Device;
+    type Vis = VisProxy;
+    type Data = DataChannel;
+
+    fn command(&mut self) -> &mut Device {
+        self
+    }
+
+    fn take_vis(&mut self) -> VisProxy {
+        std::mem::replace(&mut self.vis, VisProxy::closed())
+    }
+
+    fn take_data(&mut self) -> DataChannel {
+        std::mem::replace(&mut self.data, DataChannel::closed())
+    }
+
+    fn close(self) {
+        self.shutdown()
+    }
+}
+
+/// In-memory byte pipe that behaves like a bulk endpoint pair. Every write sends one packet of at
+/// most `packet_size` bytes which is handed out as one read, and a flush after a full packet sends
+/// a zero length packet, which reads as Ok(0). Reading from a closed pipe fails, like reading
+/// from a device that is gone. A pipe connected to itself behaves as an echo device, a pair of
+/// pipes lets a test play the device.
+pub struct Pipe {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    packet_size: usize,
+    zlp_pending: bool,
+    buffer: Vec<u8>,
+    buffer_pos: usize,
+}
+
+impl Pipe {
+    /// Creates a pipe that reads back what was written to it
+    pub fn echo(packet_size: usize) -> Self {
+        let (tx, rx) = mpsc::unbounded();
+        Pipe::new(tx, rx, packet_size)
+    }
+
+    /// Creates two pipes that read what was written to the other one
+    pub fn pair(packet_size: usize) -> (Self, Self) {
+        let (a_tx, a_rx) = mpsc::unbounded();
+        let (b_tx, b_rx) = mpsc::unbounded();
+        (Pipe::new(a_tx, b_rx, packet_size), Pipe::new(b_tx, a_rx, packet_size))
+    }
+
+    // A pipe whose other end is gone
+    fn closed(packet_size: usize) -> Self {
+        let (tx, _) = mpsc::unbounded();
+        let (_, rx) = mpsc::unbounded();
+        Pipe::new(tx, rx, packet_size)
+    }
+
+    fn new(
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+        rx: mpsc::UnboundedReceiver<Vec<u8>>,
+        packet_size: usize,
+    ) -> Self {
+        Pipe {
+            tx,
+            rx,
+            packet_size: usize::max(packet_size, 1),
+            zlp_pending: false,
+            buffer: Vec::new(),
+            buffer_pos: 0,
+        }
+    }
+}
+
+impl AsyncWrite for Pipe {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let len = usize::min(self.packet_size, buf.len());
+        match self.tx.unbounded_send(buf[..len].to_vec()) {
+            Ok(()) => {
+                self.zlp_pending = len == self.packet_size;
+                Poll::Ready(Ok(len))
+            }
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        if self.zlp_pending {
+            self.zlp_pending = false;
+            if self.tx.unbounded_send(Vec::new()).is_err() {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        self.tx.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Pipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = &mut *self;
+        if this.buffer_pos == this.buffer.len() {
+            match this.rx.poll_next_unpin(cx) {
+                // An empty packet reads as Ok(0) below
+                Poll::Ready(Some(vec)) => {
+                    this.buffer = vec;
+                    this.buffer_pos = 0;
+                }
+                Poll::Ready(None) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = usize::min(buf.len(), this.buffer.len() - this.buffer_pos);
+        buf[..len].copy_from_slice(&this.buffer[this.buffer_pos..this.buffer_pos + len]);
+        this.buffer_pos += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+/// Max packet size of a high speed bulk endpoint, used for the data channel of `Loopback`
+pub const LOOPBACK_DATA_PACKET_SIZE: usize = 512;
+
+/// In-memory transport. The command and data channels echo everything written to them, unless
+/// another command pipe is given, and the visualization stream yields whatever is pushed through
+/// the returned sender.
+pub struct Loopback {
+    command: Pipe,
+    vis: mpsc::UnboundedReceiver<VisFrame>,
+    data: Pipe,
+}
+
+impl Loopback {
+    pub fn new() -> (Self, mpsc::UnboundedSender<VisFrame>) {
+        Loopback::with_command(Pipe::echo(COMMAND_PACKET_SIZE))
+    }
+
+    /// Uses `command` as command channel, typically one end of `Pipe::pair`
+    pub fn with_command(command: Pipe) -> (Self, mpsc::UnboundedSender<VisFrame>) {
+        Loopback::with_pipes(command, Pipe::echo(LOOPBACK_DATA_PACKET_SIZE))
+    }
+
+    /// Uses `command` and `data` as command and data channel
+    pub fn with_pipes(command: Pipe, data: Pipe) -> (Self, mpsc::UnboundedSender<VisFrame>) {
+        let (vis_tx, vis_rx) = mpsc::unbounded();
+        let loopback = Loopback {
+            command,
+            vis: vis_rx,
+            data,
+        };
+        (loopback, vis_tx)
+    }
+}
+
+impl Transport for Loopback {
+    type Command = Pipe;
+    type Vis = mpsc::UnboundedReceiver<VisFrame>;
+    type Data = Pipe;
+
+    fn command(&mut self) -> &mut Pipe {
+        &mut self.command
+    }
+
+    fn take_vis(&mut self) -> mpsc::UnboundedReceiver<VisFrame> {
+        let (_, closed) = mpsc::unbounded();
+        std::mem::replace(&mut self.vis, closed)
+    }
+
+    fn take_data(&mut self) -> Pipe {
+        let packet_size = self.data.packet_size;
+        std::mem::replace(&mut self.data, Pipe::closed(packet_size))
+    }
+
+    fn close(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pipe_ends_full_packets_with_zlp() {
+        let mut pipe = Pipe::echo(COMMAND_PACKET_SIZE);
+        pipe.write_all(&[0x55; 128]).await.unwrap();
+        pipe.flush().await.unwrap();
+
+        let mut packet = [0u8; COMMAND_PACKET_SIZE];
+        assert_eq!(pipe.read(&mut packet[..]).await.unwrap(), 64);
+        assert_eq!(pipe.read(&mut packet[..]).await.unwrap(), 64);
+        assert_eq!(pipe.read(&mut packet[..]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn read_message_stops_at_expected_len() {
+        let mut pipe = Pipe::echo(COMMAND_PACKET_SIZE);
+        // No flush, so there is no zero length packet ending the message
+        pipe.write_all(&[0x55; 64]).await.unwrap();
+        pipe.write_all(&[0x66; 3]).await.unwrap();
+
+        let msg = read_message(&mut pipe, |_| Some(64)).await.unwrap();
+        assert_eq!(msg, vec![0x55; 64]);
+        let msg = read_message(&mut pipe, |_| None).await.unwrap();
+        assert_eq!(msg, vec![0x66; 3]);
+    }
+}
@@ -3,12 +3,26 @@ use futures::channel::mpsc;
 use futures::channel::oneshot;
 use futures::lock::Mutex;
 use futures::prelude::*;
-use libusb::{Context as CxUsb, DeviceHandle};
+use libusb::Context as CxUsb;
+use crate::calibration::{
+    ActiveCalibration, Calibration, CalibrationError, CalibrationStore, ChannelCalibration,
+};
+use crate::clock::SharedClockHistory;
+use crate::deviceinfo::{AcquisitionState, DeviceEvent, DeviceInfo};
+use crate::rawusb::RawContext;
+use crate::protocol::{self, Command, Frame, Notification, RecordingBlock, Response};
+use crate::samples::{SampleBlock, SampleDecoder};
+use crate::stream::{LiveStream, SampleSubscription, StreamCounters, StreamStats, SAMPLE_QUEUE_LEN};
+use crate::transport::{MessageReader, Transport};
 use crate::usbfutures::Device;
 use std::collections::{hash_map::Entry, HashMap};
+use std::io;
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::SystemTime;
+use thiserror::Error;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
 
 ///  USB Consts
 pub const VID: u16 = 0x0483;
@@ -20,89 +34,315 @@ pub const EP_IN: u8 = 0x81;
 //const EP_IN: u8 = 0x85;
 pub const EP_DATA_OUT: u8 = 0x07;
 pub const EP_DATA_IN: u8 = 0x86;
+/// Max packet size of EP_OUT/EP_IN
+pub const COMMAND_PACKET_SIZE: usize = 64;
+/// Longest message accepted on the command channel
+pub const MAX_MESSAGE_LEN: usize = 7 + 7609;
+
+// Number of events kept for slow subscribers before they start lagging
+const EVENT_QUEUE_LEN: usize = 64;
+/// Time the device has to answer a request unless the request says otherwise
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+// Notifications kept for a client that does not read them before they are dropped
+const NOTIFICATION_QUEUE_LEN: usize = 64;
+// Requests sent to the device before waiting for responses. Must stay well below the 256
+// sequence numbers so that a late response is never taken for the answer to a newer request.
+const MAX_IN_FLIGHT: usize = 16;
+// Quiet time on the data channel after which the rest of a failed block is taken to be gone
+const STALE_DATA_IDLE: Duration = Duration::from_millis(100);
 
 struct DeviceEntry {
     acquired: DeviceAcquiredState,
-    manufacturer: String,
-    product: String,
-    serial: String,
-    bcd_device: String,
+    // The state field is filled in by `DeviceEntry::info`
+    info: DeviceInfo,
+    events: broadcast::Sender<DeviceEvent>,
+    // Live stream of the device, see `USBDevices::subscribe_samples`
+    samples_tx: broadcast::Sender<SampleBlock>,
+    stream_counters: Arc<StreamCounters>,
+    // Calibration the live stream is converted with, shared with the device loop
+    calibration: ActiveCalibration,
+}
+
+// Messages from the registry to a running `device_loop` or `suspended_loop`
+pub(crate) enum Control {
+    // Close the device, then drop the Sender
+    Close(oneshot::Sender<()>),
+    // Close the device and hand back the session so that it can continue on another transport
+    Detach(oneshot::Sender<Session>),
+}
+
+// The channels a client talks to. They outlive the device loop when a device is replugged.
+pub(crate) struct Session {
+    in_rx: mpsc::Receiver<Request>,
+    // Notifications the device raised on its own
+    out_tx: mpsc::Sender<Notification>,
+    // Notifications dropped since the client last kept up. A client that never reads them, like a
+    // device acquired through the REST API, is only warned about once.
+    dropped_notifications: usize,
+    // None when the samples of the device can't be decoded
+    live: Option<LiveStream>,
+}
+
+pub type Reply = Result<Response, RequestError>;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    #[error("no response within {0:?}")]
+    Timeout(Duration),
+    #[error("device is not connected")]
+    Disconnected,
+    #[error("i/o failed: {0}")]
+    Io(String),
+    #[error("protocol error: {0}")]
+    Protocol(#[from] protocol::Error),
+}
+
+/// A command for the device loop, answered through the receiver returned with it
+pub struct Request {
+    pub command: Command,
+    pub timeout: Duration,
+    reply_tx: oneshot::Sender<Reply>,
+}
+
+impl Request {
+    pub fn new(command: Command) -> (Self, oneshot::Receiver<Reply>) {
+        Request::with_timeout(command, DEFAULT_REQUEST_TIMEOUT)
+    }
+
+    pub fn with_timeout(command: Command, timeout: Duration) -> (Self, oneshot::Receiver<Reply>) {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let request = Request {
+            command,
+            timeout,
+            reply_tx,
+        };
+        (request, reply_rx)
+    }
+
+    fn reply(self, reply: Reply) {
+        // The requester may have given up waiting
+        let _ = self.reply_tx.send(reply);
+    }
+}
+
+// A request sent to the device, waiting for its response
+struct PendingRequest {
+    request: Request,
+    deadline: Instant,
 }
 
 enum DeviceAcquiredState {
     Available,
-    Acquired(mpsc::Sender<oneshot::Sender<()>>),
+    Acquired(mpsc::Sender<Control>),
+    // Acquired, but the device has left. The session is kept by a `suspended_loop` and resumed
+    // when the device comes back.
+    Suspended(mpsc::Sender<Control>),
 }
 
 impl std::fmt::Debug for DeviceEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        let product = self.info.product.as_deref().unwrap_or("unknown");
         match &self.acquired {
-            DeviceAcquiredState::Available => write!(f, "Avilable ({})", self.product)?,
+            DeviceAcquiredState::Available => write!(f, "Available ({})", product)?,
             DeviceAcquiredState::Acquired { .. } => write!(f, "Acquired")?,
+            DeviceAcquiredState::Suspended { .. } => write!(f, "Suspended")?,
         }
         Ok(())
     }
 }
 
 impl DeviceEntry {
-    pub fn new(manufacturer: &str, product: &str, serial: &str, bcd_device: &str) -> Self {
+    pub fn new(info: DeviceInfo, events: broadcast::Sender<DeviceEvent>) -> Self {
+        let (samples_tx, _) = broadcast::channel(SAMPLE_QUEUE_LEN);
         DeviceEntry {
             acquired: DeviceAcquiredState::Available,
-            manufacturer: manufacturer.to_string(),
-            product: product.to_string(),
-            serial: serial.to_string(),
-            bcd_device: bcd_device.to_string(),
+            info,
+            events,
+            samples_tx,
+            stream_counters: Default::default(),
+            calibration: Default::default(),
         }
     }
 
-    pub fn manufacturer(&self) -> &str {
-        &self.manufacturer
+    pub fn info(&self) -> DeviceInfo {
+        let state = match &self.acquired {
+            DeviceAcquiredState::Available => AcquisitionState::Available,
+            DeviceAcquiredState::Acquired(_) => AcquisitionState::Acquired,
+            DeviceAcquiredState::Suspended(_) => AcquisitionState::Suspended,
+        };
+        DeviceInfo {
+            state,
+            ..self.info.clone()
+        }
     }
 
-    pub fn product(&self) -> &str {
-        &self.product
+    pub fn path(&self) -> Option<&str> {
+        self.info.path.as_deref()
     }
 
-    pub fn serial(&self) -> &str {
-        &self.serial
+    pub fn serial(&self) -> Option<&str> {
+        self.info.serial.as_deref()
     }
 
-    pub fn bcd_device(&self) -> &str {
-        &self.bcd_device
+    // Decoder of the live stream for the device loop, if the firmware is known. Samples are
+    // converted with `calibration`, the one stored for the serial number if there is one.
+    fn live_stream(&self, calibration: Option<Calibration>) -> Option<LiveStream> {
+        self.calibration.set(calibration);
+        match SampleDecoder::new(self.info.firmware_version) {
+            Ok(decoder) => Some(LiveStream::new(
+                decoder,
+                self.calibration.clone(),
+                Arc::clone(&self.stream_counters),
+                self.samples_tx.clone(),
+            )),
+            Err(e) => {
+                warn!("No live stream for {}: {}", self.info.id, e);
+                None
+            }
+        }
     }
 
-    pub fn acquire(&mut self, tx: mpsc::Sender<oneshot::Sender<()>>) {
+    pub fn acquire(&mut self, tx: mpsc::Sender<Control>) {
         self.acquired = DeviceAcquiredState::Acquired(tx);
+        self.emit(DeviceEvent::Acquired {
+            id: self.info.id.clone(),
+        });
     }
 
-    pub async fn release(&mut self) {
-        match &mut self.acquired {
-            DeviceAcquiredState::Acquired(tx) => {
-                // We use a oneshot channel to communicate that the device has been successfully
-                // dropped. The "device_loop" task will first drop the device and then drop this
-                // Sender.
-                let (close_tx, close_rx) = oneshot::channel();
-                if let Err(_e) = tx.send(close_tx).await {
-                    error!("failed to send");
-                }
-                let _ = close_rx.await; // Error here is expected
-            }
-            _ => (),
+    // Control channel of the loop serving the session, if the device is acquired
+    fn control(&self) -> Option<mpsc::Sender<Control>> {
+        match &self.acquired {
+            DeviceAcquiredState::Available => None,
+            DeviceAcquiredState::Acquired(tx) | DeviceAcquiredState::Suspended(tx) => Some(tx.clone()),
+        }
+    }
+
+    // Marks the device released once its session has been closed, see `close_session`
+    fn set_released(&mut self) {
+        if let DeviceAcquiredState::Available = self.acquired {
+            return;
         }
         self.acquired = DeviceAcquiredState::Available;
+        self.emit(DeviceEvent::Released {
+            id: self.info.id.clone(),
+        });
+    }
+
+    fn emit(&self, event: DeviceEvent) {
+        // Fails only when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    // Control channel of the device loop of an acquired device, for taking its session when the
+    // device has left
+    fn detaching(&self) -> Option<mpsc::Sender<Control>> {
+        match &self.acquired {
+            DeviceAcquiredState::Acquired(tx) => Some(tx.clone()),
+            _ => None,
+        }
+    }
+
+    // Keeps the session taken from the device loop of a device that has left
+    fn suspend(&mut self, session: Option<Session>) {
+        self.acquired = match session {
+            Some(session) => DeviceAcquiredState::Suspended(spawn_suspended_loop(session)),
+            // The device loop has already ended, nothing to resume
+            None => DeviceAcquiredState::Available,
+        };
+    }
+
+    // Control channel of the `suspended_loop` of a device that is back, for taking its session to
+    // resume it. The device is available until then.
+    fn resuming(&mut self) -> Option<mpsc::Sender<Control>> {
+        match std::mem::replace(&mut self.acquired, DeviceAcquiredState::Available) {
+            DeviceAcquiredState::Suspended(tx) => Some(tx),
+            other => {
+                self.acquired = other;
+                None
+            }
+        }
+    }
+
+    // Whether the entry holds a session that a client is still using
+    fn has_live_session(&self) -> bool {
+        match &self.acquired {
+            DeviceAcquiredState::Suspended(tx) => !tx.is_closed(),
+            _ => false,
+        }
+    }
+}
+
+// Ends the session served through `tx`. Returns once the device has been dropped.
+async fn close_session(mut tx: mpsc::Sender<Control>) {
+    // We use a oneshot channel to communicate that the device has been successfully dropped. The
+    // "device_loop" task will first drop the device and then drop this Sender.
+    let (close_tx, close_rx) = oneshot::channel();
+    if let Err(_e) = tx.send(Control::Close(close_tx)).await {
+        error!("failed to send");
+    }
+    let _ = close_rx.await; // Error here is expected
+}
+
+// Takes the session served through `tx`, after closing the device if there is one. None if the loop
+// has already ended, or if the client went away meanwhile.
+async fn take_session(mut tx: mpsc::Sender<Control>) -> Option<Session> {
+    let (session_tx, session_rx) = oneshot::channel();
+    if let Err(_e) = tx.send(Control::Detach(session_tx)).await {
+        error!("failed to send");
+    }
+    session_rx.await.ok()
+}
+
+/// Subscription to the device events of a `USBDevices`
+pub struct DeviceEvents {
+    rx: broadcast::Receiver<DeviceEvent>,
+}
+
+impl DeviceEvents {
+    /// Waits for the next event. A subscriber that falls too far behind gets a
+    /// `DeviceEvent::Lagged` in place of the events it missed. Returns None when the registry is
+    /// gone.
+    pub async fn next(&mut self) -> Option<DeviceEvent> {
+        match self.rx.recv().await {
+            Ok(event) => Some(event),
+            Err(broadcast::RecvError::Lagged(missed)) => {
+                warn!("Device event subscriber lagged, {} events missed", missed);
+                Some(DeviceEvent::Lagged { missed })
+            }
+            Err(broadcast::RecvError::Closed) => None,
+        }
     }
 }
 
 pub struct USBDevices {
+    // Keyed by the stable device id, see `device_id`
     devices: Arc<Mutex<HashMap<String, DeviceEntry>>>,
+    events: broadcast::Sender<DeviceEvent>,
+    calibrations: Arc<Mutex<CalibrationStore>>,
+    // Held from storing a calibration until its file is written, so that the files are written in
+    // the order the calibrations were stored
+    saving: Arc<Mutex<()>>,
+    // Held while devices are acquired, released or attached, which waits for their loops. The
+    // registry itself is only locked in between, so that it can be read meanwhile.
+    transitions: Arc<Mutex<()>>,
+    // Clock syncs of every monitor seen, keyed by serial number, so that the drift is measured
+    // across replugs
+    clocks: Arc<Mutex<HashMap<String, SharedClockHistory>>>,
     libusb: &'static CxUsb,
+    raw: &'static RawContext,
 }
 
 impl Clone for USBDevices {
     fn clone(&self) -> Self {
         USBDevices {
             devices: Arc::clone(&self.devices),
+            events: self.events.clone(),
+            calibrations: Arc::clone(&self.calibrations),
+            saving: Arc::clone(&self.saving),
+            transitions: Arc::clone(&self.transitions),
+            clocks: Arc::clone(&self.clocks),
             libusb: self.libusb,
+            raw: self.raw,
         }
     }
 }
@@ -113,34 +353,124 @@ impl USBDevices {
         
         let cx = Box::new(CxUsb::new()?);
         let cx = Box::leak(cx);
+        let raw = Box::leak(Box::new(RawContext::new()?));
+
+        let (events, _) = broadcast::channel(EVENT_QUEUE_LEN);
 
         Ok(USBDevices {
             devices: Default::default(),
+            events,
+            calibrations: Default::default(),
+            saving: Default::default(),
+            transitions: Default::default(),
+            clocks: Default::default(),
             libusb: cx,
+            raw,
         })
     }
-    pub async fn devices(&self) -> Vec<HashMap<String, String>> {
+    pub async fn devices(&self) -> Vec<DeviceInfo> {
         self.devices
             .lock()
             .await
-            .iter()
-            .map(|device| {
-                let mut d = HashMap::new();
-                d.insert(
-                    "path".into(),
-                    device.0.to_string(),
-                );
-                d.insert("manufacturer".into(), device.1.manufacturer().to_string());
-                d.insert("product".into(), device.1.product().to_string());
-                d.insert("serial".into(), device.1.serial().to_string());
-                d.insert("bcdDevice".into(), device.1.bcd_device().to_string());
-                d
-            })
+            .values()
+            .map(DeviceEntry::info)
             .collect()
     }
 
+    pub async fn device(&self, id: &str) -> Option<DeviceInfo> {
+        self.devices.lock().await.get(id).map(DeviceEntry::info)
+    }
+
+    /// Replaces the calibrations used for devices acquired from now on
+    pub async fn set_calibrations(&self, calibrations: CalibrationStore) {
+        *self.calibrations.lock().await = calibrations;
+    }
+
+    /// Calibration the samples of a device are converted with, None while they are in ADC counts
+    pub async fn calibration(&self, id: &str) -> Option<Calibration> {
+        let devices = self.devices.lock().await;
+        devices.get(id)?.calibration.get()
+    }
+
+    /// Stores a measured calibration for the serial number of a device and converts its samples
+    /// with it from now on. The file is written on the blocking pool once the registry is
+    /// unlocked.
+    pub async fn store_calibration(
+        &self,
+        id: &str,
+        channels: Vec<ChannelCalibration>,
+    ) -> Result<Calibration, CalibrationError> {
+        let _saving = self.saving.lock().await;
+        let (calibration, file) = {
+            let devices = self.devices.lock().await;
+            let entry = devices.get(id).ok_or_else(|| CalibrationError::NotFound(id.to_string()))?;
+            let serial = entry.serial().ok_or_else(|| CalibrationError::NoSerial(id.to_string()))?;
+            let stored = self.calibrations.lock().await.store(serial, channels);
+            entry.calibration.set(Some(stored.0.clone()));
+            info!("Stored the calibration of {}", serial);
+            stored
+        };
+        if let Some(file) = file {
+            tokio::task::spawn_blocking(move || file.save())
+                .await
+                .map_err(io::Error::other)??;
+        }
+        Ok(calibration)
+    }
+
+    /// History of the clock syncs of a device, shared by all its clients. Devices without a serial
+    /// number keep it under their id.
+    pub async fn clock_history(&self, id: &str) -> Option<SharedClockHistory> {
+        let devices = self.devices.lock().await;
+        let key = devices.get(id)?.serial().unwrap_or(id);
+        Some(self.clocks.lock().await.entry(key.to_string()).or_default().clone())
+    }
+
+    /// Subscribes to the samples of a device. They flow while the device is acquired.
+    pub async fn subscribe_samples(&self, id: &str) -> Option<SampleSubscription> {
+        let devices = self.devices.lock().await;
+        let entry = devices.get(id)?;
+        Some(SampleSubscription::new(entry.samples_tx.subscribe()))
+    }
+
+    /// Packets received and lost on the live stream of a device since it was first seen
+    pub async fn stream_stats(&self, id: &str) -> Option<StreamStats> {
+        let devices = self.devices.lock().await;
+        devices.get(id).map(|entry| entry.stream_counters.stats())
+    }
+
+    /// Subscribes to device events. Every subscriber gets its own copy of each event.
+    pub fn subscribe(&self) -> DeviceEvents {
+        DeviceEvents {
+            rx: self.events.subscribe(),
+        }
+    }
+
     pub async fn presence_detector(
         self,
+        notify_rx: mpsc::Receiver<()>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (hotplug_tx, hotplug_rx) = mpsc::channel(1);
+        match self.raw.watch_hotplug(VID, PID, hotplug_tx) {
+            Ok(()) => {
+                info!("Using hotplug notifications");
+                let mut notifications = stream::select(notify_rx, hotplug_rx);
+                while notifications.next().await.is_some() {
+                    self.refresh().await?;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                info!("Polling for devices: {}", e);
+                self.poll(notify_rx).await
+            }
+        }
+    }
+
+    // Fallback for platforms without hotplug support. Polls the bus after every notification
+    // until there have been no devices for a while.
+    async fn poll(
+        &self,
         mut notify_rx: mpsc::Receiver<()>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         loop {
@@ -154,7 +484,7 @@ impl USBDevices {
 
                 // Stop iterating in case wallets are plugged out and there haven't been any
                 // communication in a while.
-                if self.devices.lock().await.len() == 0 {
+                if self.devices.lock().await.is_empty() {
                     match last_seen {
                         None => last_seen = Some(SystemTime::now()),
                         Some(last_seen) => {
@@ -174,168 +504,1036 @@ impl USBDevices {
     pub async fn refresh(&self) -> Result<(), Box<dyn std::error::Error>> {
 
         let libusb = self.libusb;
+        let _transition = self.transitions.lock().await;
 
-        let mut seen = Vec::new();
+        // id -> path of every monitor currently plugged in
+        let mut seen = HashMap::new();
         let mut devices_guard = self.devices.lock().await;
         for device in libusb.devices().expect("No device list").iter() {
             
             let device_desc = device.device_descriptor()?;
 
             if device_desc.vendor_id() == VID && device_desc.product_id() ==  PID {
-                let path = { 
-                    device.bus_number().to_string() + ":" + &device.address().to_string()
-                };
-                let timeout = std::time::Duration::from_millis(100);
-                let (manufacturer, product, serial, bcd_device) = match device.open() {
-                    Ok(device) => {
-                        match device.read_languages(timeout) {
-                            Ok(lang) if lang.len() > 0 => {
-                                let manufacturer = device.read_manufacturer_string(lang[0], &device_desc, timeout)
-                                    .unwrap_or_else(|e| String::from(format!("manufacturer read error: {:?}", e)));
-                                let product = device.read_product_string(lang[0], &device_desc, timeout)
-                                    .unwrap_or_else(|e| String::from(format!("product read error: {:?}", e)));
-                                let serial = device.read_serial_number_string(lang[0], &device_desc, timeout)
-                                    .unwrap_or_else(|e| String::from(format!("serial read error: {:?}", e)));
-                                let version = device_desc.device_version();
-                                let bcd_device = String::from(format!("{}.{}", version.major(), version.minor()));
-                                (manufacturer, product, serial, bcd_device)
-                            }
-                            _ => {
-                                error!("Can't read lang descriptor, device: {}", &path);
-                                (String::from("none"), String::from("none"), String::from("none"), String::from(""))
-                            }
-                        }
+                let path = device_path(&device);
+                // Descriptor strings only need to be read once per device
+                if let Some((id, entry)) = devices_guard.iter_mut().find(|(_, entry)| entry.path() == Some(&path)) {
+                    entry.info.last_seen = SystemTime::now();
+                    seen.insert(id.clone(), path);
+                    continue;
+                }
+                let (manufacturer, product, serial) = read_descriptor_strings(&device, &device_desc, &path);
+                let port_chain = self
+                    .raw
+                    .port_chain(device.bus_number(), device.address())
+                    .unwrap_or_default();
+                let id = device_id(&device, &port_chain, serial.as_deref());
+                match devices_guard.entry(id.clone()) {
+                    Entry::Occupied(mut o) => {
+                        info!("Holter monitor {} is back at {}", id, path);
+                        let info = &mut o.get_mut().info;
+                        info.port_chain = port_chain;
+                        info.speed = device.speed().into();
+                        info.last_seen = SystemTime::now();
                     }
-                    Err(_e) => {
-                        error!("Can't open device: {} to read descriptor strings", &path);
-                        (String::from("none"), String::from("none"), String::from("none"), String::from(""))
+                    Entry::Vacant(v) => {
+                        info!("Found Holter monitor {} at {}!", id, path);
+                        v.insert(DeviceEntry::new(DeviceInfo {
+                            id: id.clone(),
+                            path: None,
+                            port_chain,
+                            speed: device.speed().into(),
+                            vendor_id: device_desc.vendor_id(),
+                            product_id: device_desc.product_id(),
+                            firmware_version: device_desc.device_version().into(),
+                            manufacturer,
+                            product,
+                            serial,
+                            state: AcquisitionState::Available,
+                            last_seen: SystemTime::now(),
+                        }, self.events.clone()));
                     }
-                };
+                }
+                seen.insert(id, path);
+            }
+        }
 
-                //let product = match device.product_string.as_ref() {
-                //    Some(product) => product,
-                //    None => {
-                //        warn!("ignored: no product");
-                //        continue;
-                //    }
-                //};
-                seen.push(path.clone());
-                match devices_guard.entry(path.clone()) {
-                    Entry::Occupied(_) => (),
-                    Entry::Vacant(v) => {
-                        info!("Found Holter monitor at {}!", path);
-                        v.insert(DeviceEntry::new(&manufacturer, &product, &serial, &bcd_device));
+        // Suspend the sessions of devices that left or moved and forget the rest
+        let left: Vec<(String, Option<mpsc::Sender<Control>>)> = devices_guard
+            .iter()
+            .filter(|(id, entry)| entry.info.path.is_some() && entry.info.path.as_ref() != seen.get(*id))
+            .map(|(id, entry)| (id.clone(), entry.detaching()))
+            .collect();
+        drop(devices_guard);
+        let mut suspended = HashMap::new();
+        for (id, tx) in &left {
+            if let Some(tx) = tx {
+                suspended.insert(id.clone(), take_session(tx.clone()).await);
+            }
+        }
+        let mut devices_guard = self.devices.lock().await;
+        for (id, _) in left {
+            let entry = devices_guard.get_mut(&id).expect("registry changed during a transition");
+            info!("Holter monitor {} left {}", id, entry.path().unwrap_or(""));
+            if let Some(session) = suspended.remove(&id) {
+                entry.suspend(session);
+            }
+            entry.info.path = None;
+            entry.emit(DeviceEvent::Left { id: id.clone() });
+        }
+        devices_guard.retain(|id, entry| seen.contains_key(id) || entry.has_live_session());
+
+        // Attach the devices that appeared, resuming their sessions if they had any
+        let mut resuming = Vec::new();
+        for (id, path) in seen {
+            let entry = match devices_guard.get_mut(&id) {
+                Some(entry) if entry.info.path.is_none() => entry,
+                _ => continue,
+            };
+            entry.info.path = Some(path.clone());
+            entry.emit(DeviceEvent::Arrived(entry.info()));
+            if let Some(tx) = entry.resuming() {
+                resuming.push((id, path, tx));
+            }
+        }
+        drop(devices_guard);
+        for (id, path, tx) in resuming {
+            let session = match take_session(tx).await {
+                Some(session) => session,
+                None => continue,
+            };
+            match self.open(&path) {
+                Ok(device) => {
+                    info!("Resumed session of {} at {}", id, path);
+                    let control_tx = spawn_session_loop(device, session);
+                    if let Some(entry) = self.devices.lock().await.get_mut(&id) {
+                        entry.acquire(control_tx);
                     }
                 }
+                Err(e) => error!("Failed to resume session of {}: {}", id, e),
             }
         }
-        // Remove all devices that wasn't seen
-        devices_guard.retain(|k, _| seen.contains(&k));
         Ok(())
     }
 
     pub async fn acquire_device(
         &self,
-        path: &str,
-    ) -> Result<Option<(mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>)>, Box<dyn std::error::Error>>
+        id: &str,
+    ) -> Result<Option<(mpsc::Sender<Request>, mpsc::Receiver<Notification>)>, Box<dyn std::error::Error>>
     {
-        if let Some(device) = self.devices.lock().await.get_mut(path) {
-            // Make sure device is released
-            device.release().await;
+        let _transition = self.transitions.lock().await;
+        let (control, path, serial) = match self.devices.lock().await.get(id) {
+            Some(entry) => (entry.control(), entry.info.path.clone(), entry.info.serial.clone()),
+            None => {
+                info!("Failed to acquire device: {}", id);
+                return Ok(None);
+            }
+        };
+        // Make sure device is released
+        if let Some(tx) = control {
+            close_session(tx).await;
+        }
+        if let Some(entry) = self.devices.lock().await.get_mut(id) {
+            entry.set_released();
+        }
 
-            let (in_tx, in_rx) = mpsc::channel(128);
-            let (out_tx, out_rx) = mpsc::channel(128);
-            
-            // TODO: use path
-            let libusb_device = match self.libusb.open_device_with_vid_pid(VID, PID) {
-                Some(device) => device,
-                None => Err(libusb::Error::NoDevice)?
-            };
-            let libusb_device = Device::new(libusb_device)?;
-            info!("Successfully acquired device: {}", path);
-            let (on_close_tx, on_close_rx) = mpsc::channel(1);
-            device.acquire(on_close_tx);
-            tokio::spawn(device_loop(libusb_device, in_rx, out_tx, on_close_rx));
-            Ok(Some((in_tx, out_rx)))
-        } else {
-            info!("Failed to acquire device: {}", path);
-            Ok(None)
-        }
-    }
-}
-
-async fn handle_msg(
-    device: &mut Device,
-    msg: Vec<u8>,
-    out_tx: &mut mpsc::Sender<Vec<u8>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    //let (cid, cmd, _) = u2fframing::parse_header(&msg[..])?;
-
-    //let mut wscodec = U2FWS::with_cid(cid, cmd);
-    //let res = wscodec.decode(&msg[..])?.ok_or(std::io::Error::new(
-    //    std::io::ErrorKind::Other,
-    //    "not enough data in websocket message",
-    //))?;
-
-    //let mut hidcodec = U2FHID::new(cmd);
-    let mut buf = [0u8; 7 + 7609]; // Maximally supported size by u2f
-    //let len = hidcodec.encode(&res[..], &mut buf[..])?;
-    
-    device.write_all(&msg[..]).await?;
-
-    let mut len = 0;
-    loop {
-        let this_len = device.read(&mut buf[len..]).await?;
-        len += this_len;
+        let path = match path {
+            Some(path) => path,
+            None => {
+                info!("Failed to acquire device: {} is not plugged in", id);
+                return Ok(None);
+            }
+        };
+        let libusb_device = self.open(&path)?;
+        info!("Successfully acquired device: {} at {}", id, path);
+        let calibration = match serial {
+            Some(serial) => self.calibrations.lock().await.lookup(&serial).cloned(),
+            None => None,
+        };
+        let mut devices = self.devices.lock().await;
+        let entry = devices.get_mut(id).expect("registry changed during a transition");
+        let live = entry.live_stream(calibration);
+        let (in_tx, out_rx, control_tx) = spawn_device_loop(libusb_device, live);
+        entry.acquire(control_tx);
+        Ok(Some((in_tx, out_rx)))
+    }
 
-        if let Err(e) = out_tx.send(buf[..len].to_vec()).await {
-            error!("Failed to send internally: {}", e);
+    /// Releases the device. Returns false if there is no such device.
+    pub async fn release_device(&self, id: &str) -> bool {
+        let _transition = self.transitions.lock().await;
+        let control = match self.devices.lock().await.get(id) {
+            Some(entry) => entry.control(),
+            None => return false,
+        };
+        if let Some(tx) = control {
+            close_session(tx).await;
+        }
+        if let Some(entry) = self.devices.lock().await.get_mut(id) {
+            entry.set_released();
         }
-        break;
+        info!("Released device: {}", id);
+        true
+    }
 
-        ////let res = hidcodec.decode(&buf[..len])?;
-        //if let Some(res) = res {
-        //    if let Ok(len) = wscodec.encode(&res[..], &mut buf[..]) {
-        //        if let Err(e) = out_tx.send(buf[..len].to_vec()).await {
-        //            error!("Failed to send internally: {}", e);
-        //        }
-        //    }
-        //    break;
-        //}
-        // Loop to read out more data from device
+    // Opens the monitor at bus:address
+    fn open(&self, path: &str) -> Result<Device, Box<dyn std::error::Error>> {
+        let libusb_device = match self
+            .libusb
+            .devices()?
+            .iter()
+            .find(|device| is_holter(device) && device_path(device) == path)
+        {
+            Some(device) => device,
+            None => Err(libusb::Error::NoDevice)?
+        };
+        let data_max_packet_size = endpoint_max_packet_size(&libusb_device, EP_DATA_IN)
+            .unwrap_or(64);
+        Ok(Device::new(libusb_device.open()?, data_max_packet_size.into())?)
     }
-    Ok(())
 }
 
-async fn device_loop(
-    mut device: Device,
-    mut in_rx: mpsc::Receiver<Vec<u8>>,
-    mut out_tx: mpsc::Sender<Vec<u8>>,
-    mut on_close_rx: mpsc::Receiver<oneshot::Sender<()>>,
+// Stable id of a monitor. The serial number follows the device across replugs; without one the
+// port chain is used, which at least stays the same as long as the device is put back in the
+// same port.
+fn device_id(device: &libusb::Device, port_chain: &[u8], serial: Option<&str>) -> String {
+    match serial {
+        Some(serial) if !serial.is_empty() => serial.to_string(),
+        _ if !port_chain.is_empty() => {
+            let ports: Vec<String> = port_chain.iter().map(|port| port.to_string()).collect();
+            format!("{}-{}", device.bus_number(), ports.join("."))
+        }
+        _ => device_path(device),
+    }
+}
+
+// Reads the manufacturer, product and serial number strings. Failures are logged and the string
+// left out.
+fn read_descriptor_strings(
+    device: &libusb::Device,
+    device_desc: &libusb::DeviceDescriptor,
+    path: &str,
+) -> (Option<String>, Option<String>, Option<String>) {
+    let timeout = std::time::Duration::from_millis(100);
+    let handle = match device.open() {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("Can't open device: {} to read descriptor strings: {}", path, e);
+            return (None, None, None);
+        }
+    };
+    let lang = match handle.read_languages(timeout) {
+        Ok(langs) if !langs.is_empty() => langs[0],
+        _ => {
+            error!("Can't read lang descriptor, device: {}", path);
+            return (None, None, None);
+        }
+    };
+    let log_error = |what: &str, e: libusb::Error| error!("Can't read {} of {}: {}", what, path, e);
+    let manufacturer = handle
+        .read_manufacturer_string(lang, device_desc, timeout)
+        .map_err(|e| log_error("manufacturer", e))
+        .ok();
+    let product = handle
+        .read_product_string(lang, device_desc, timeout)
+        .map_err(|e| log_error("product", e))
+        .ok();
+    let serial = handle
+        .read_serial_number_string(lang, device_desc, timeout)
+        .map_err(|e| log_error("serial", e))
+        .ok();
+    (manufacturer, product, serial)
+}
+
+// Path of a device as used for the keys in `USBDevices::devices`
+fn device_path(device: &libusb::Device) -> String {
+    device.bus_number().to_string() + ":" + &device.address().to_string()
+}
+
+fn is_holter(device: &libusb::Device) -> bool {
+    match device.device_descriptor() {
+        Ok(desc) => desc.vendor_id() == VID && desc.product_id() == PID,
+        Err(_) => false,
+    }
+}
+
+// Looks up an endpoint in the active configuration and returns its max packet size
+fn endpoint_max_packet_size(device: &libusb::Device, address: u8) -> Option<u16> {
+    let config = device.active_config_descriptor().ok()?;
+    for interface in config.interfaces() {
+        for descriptor in interface.descriptors() {
+            for endpoint in descriptor.endpoint_descriptors() {
+                if endpoint.address() == address {
+                    return Some(endpoint.max_packet_size());
+                }
+            }
+        }
+    }
+    None
+}
+
+// Spawns the task that owns the transport and returns the channels used to talk to it
+pub(crate) fn spawn_device_loop<T: Transport>(
+    device: T,
+    live: Option<LiveStream>,
+) -> (
+    mpsc::Sender<Request>,
+    mpsc::Receiver<Notification>,
+    mpsc::Sender<Control>,
+) {
+    let (in_tx, in_rx) = mpsc::channel(128);
+    let (out_tx, out_rx) = mpsc::channel(NOTIFICATION_QUEUE_LEN);
+    let session = Session {
+        in_rx,
+        out_tx,
+        dropped_notifications: 0,
+        live,
+    };
+    let control_tx = spawn_session_loop(device, session);
+    (in_tx, out_rx, control_tx)
+}
+
+// Spawns the task that serves an existing session on the transport
+fn spawn_session_loop<T: Transport>(device: T, session: Session) -> mpsc::Sender<Control> {
+    let (control_tx, control_rx) = mpsc::channel(1);
+    tokio::spawn(device_loop(device, session, control_rx));
+    control_tx
+}
+
+async fn device_loop<T: Transport>(
+    mut device: T,
+    mut session: Session,
+    mut control_rx: mpsc::Receiver<Control>,
 ) {
+    match serve(&mut device, &mut session, &mut control_rx).await {
+        Served::Control(Control::Close(_close_tx)) => {
+            // We close the device explitly so that it is dropped before the Sender we were sent
+            close(device).await;
+        }
+        Served::Control(Control::Detach(session_tx)) => {
+            close(device).await;
+            let _ = session_tx.send(session);
+        }
+        Served::ClientGone => info!("Client dropped the device channel"),
+        // When the device is plugged out, the other end of the channel will be dropped and then
+        // the stream ends.
+        Served::RegistryGone => info!("Device was plugged out"),
+    }
+}
+
+// Spawns the task that keeps a session while its device is gone
+fn spawn_suspended_loop(session: Session) -> mpsc::Sender<Control> {
+    let (control_tx, control_rx) = mpsc::channel(1);
+    tokio::spawn(suspended_loop(session, control_rx));
+    control_tx
+}
+
+// Answers the requests of a session whose device has left with `Disconnected`, so that its client
+// doesn't wait for the device to come back. Hands the session back on `Control::Detach`, and ends
+// once the client is gone or the registry closes the session.
+async fn suspended_loop(mut session: Session, mut control_rx: mpsc::Receiver<Control>) {
     loop {
         tokio::select! {
-            msg = in_rx.next() => {
-                if let Some(msg) = msg {
-                    if let Err(e) = handle_msg(&mut device, msg, &mut out_tx).await {
+            req = session.in_rx.next() => match req {
+                Some(req) => req.reply(Err(RequestError::Disconnected)),
+                None => return,
+            },
+            control = control_rx.next() => {
+                if let Some(Control::Detach(session_tx)) = control {
+                    let _ = session_tx.send(session);
+                }
+                return;
+            },
+        }
+    }
+}
+
+// Why `serve` returned
+enum Served {
+    // The registry closes or detaches the device
+    Control(Control),
+    // The client dropped its end of the requests
+    ClientGone,
+    // The registry dropped its end of the control channel
+    RegistryGone,
+}
+
+// Closing joins the reader threads, which may have to wait for a read to time out
+async fn close<T: Transport>(device: T) {
+    if let Err(e) = tokio::task::spawn_blocking(move || device.close()).await {
+        error!("Failed to close the device: {}", e);
+    }
+}
+
+// Sends requests as they come in, up to MAX_IN_FLIGHT at a time, and matches responses to them by
+// sequence number in whatever order they arrive. EP_IN is read all the time, so notifications are
+// passed on as soon as the device raises them. Returns why the session ended.
+async fn serve<T: Transport>(
+    device: &mut T,
+    session: &mut Session,
+    control_rx: &mut mpsc::Receiver<Control>,
+) -> Served {
+    // The channels are borrowed afresh in every branch. The select only runs a handler after it
+    // dropped all branch futures, so a handler may use the device.
+    let mut message_reader = MessageReader::default();
+    let mut pending: HashMap<u8, PendingRequest> = HashMap::new();
+    let mut sequence = 0u8;
+    // Cleared once reading from the transport fails. Requests fail right away from then on, until
+    // the registry closes or detaches the device.
+    let mut connected = true;
+    // Blocks are read from the data channel in their own task, which answers their requests
+    let (block_tx, block_rx) = mpsc::unbounded();
+    tokio::spawn(read_blocks(device.take_data(), block_rx));
+    // Read all the time, decoded only if the samples of the device can be decoded
+    let mut vis = device.take_vis();
+    let mut vis_open = true;
+    if matches!(&session.live, Some(live) if live.needs_config()) {
+        // The nominal calibration depends on the configured gain. Nobody waits for the reply, the
+        // config is picked up from it like from any other.
+        let (req, _) = Request::new(Command::GetConfig);
+        send_request(device.command(), &mut pending, &mut sequence, req).await;
+    }
+
+    let served = loop {
+        let deadline = pending.values().map(|p| p.deadline).min();
+        tokio::select! {
+            req = session.in_rx.next(), if pending.len() < MAX_IN_FLIGHT => {
+                let req = match req {
+                    Some(req) => req,
+                    None => break Served::ClientGone,
+                };
+                if !connected {
+                    req.reply(Err(RequestError::Disconnected));
+                    continue;
+                }
+                send_request(device.command(), &mut pending, &mut sequence, req).await;
+            },
+            msg = message_reader.read(device.command(), protocol::frame_len), if connected => {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    // A message that can't be a frame is dropped like a corrupted one
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => {
                         error!("message ignored: {}", e);
+                        continue;
                     }
-                } else {
-                    error!("dev channel closed");
-                    return;
+                    Err(e) => {
+                        error!("Reading from device failed: {}", e);
+                        connected = false;
+                        for (_, p) in pending.drain() {
+                            p.request.reply(Err(RequestError::Io(e.to_string())));
+                        }
+                        continue;
+                    }
+                };
+                // A corrupted frame can't be matched, its request times out
+                let frame = match Frame::decode(&msg[..]) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        error!("message ignored: {}", e);
+                        continue;
+                    }
+                };
+                if frame.is_notification() {
+                    notify(session, frame);
+                    continue;
+                }
+                // A response of another kind leaves the request waiting for its own
+                let p = match pending.get(&frame.sequence) {
+                    Some(p) if frame.answers(p.request.command.id()) => {
+                        pending.remove(&frame.sequence).unwrap()
+                    }
+                    Some(p) => {
+                        warn!(
+                            "Response {:#04x} does not answer command {:#04x} of request {}",
+                            frame.id,
+                            p.request.command.id(),
+                            frame.sequence
+                        );
+                        continue;
+                    }
+                    None => {
+                        warn!("Response to unknown or expired request {}", frame.sequence);
+                        continue;
+                    }
+                };
+                let res = Response::from_frame(frame).map_err(RequestError::from);
+                if let (Ok(Response::Config(config)), Some(live)) = (&res, session.live.as_mut()) {
+                    live.configure(config);
+                }
+                match res {
+                    // The block follows on the data channel. Its length comes from the device and
+                    // is checked before a buffer is allocated for it.
+                    Ok(Response::Block(block)) => match check_block_len(&p.request.command, &block) {
+                        Ok(()) => {
+                            if let Err(e) = block_tx.unbounded_send(BlockRead { request: p.request, block }) {
+                                e.into_inner().request.reply(Err(RequestError::Disconnected));
+                            }
+                        }
+                        Err(e) => p.request.reply(Err(e.into())),
+                    },
+                    res => p.request.reply(res),
                 }
             },
-            close_tx = on_close_rx.next() => {
-                if let Some(_close_tx) = close_tx {
-                    // We drop the device explitly so that it is dropped before the Sender we were sent
-                    drop(device);
-                } else {
-                    // When the device is plugged out, the other end of the channel will be dropped and
-                    // then this future will resolve to None since the stream has ended.
-                    info!("Device was plugged out");
+            _ = tokio::time::delay_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let now = Instant::now();
+                let expired: Vec<u8> = pending
+                    .iter()
+                    .filter(|(_, p)| p.deadline <= now)
+                    .map(|(sequence, _)| *sequence)
+                    .collect();
+                for sequence in expired {
+                    if let Some(p) = pending.remove(&sequence) {
+                        let timeout = p.request.timeout;
+                        p.request.reply(Err(RequestError::Timeout(timeout)));
+                    }
                 }
-                return;
+            },
+            frame = vis.next(), if vis_open => match frame {
+                Some(frame) => {
+                    if let Some(live) = session.live.as_mut() {
+                        live.push(frame);
+                    }
+                }
+                None => {
+                    warn!("Visualization stream ended");
+                    vis_open = false;
+                }
+            },
+            control = control_rx.next() => match control {
+                Some(control) => break Served::Control(control),
+                None => break Served::RegistryGone,
+            },
+        }
+    };
+    for (_, p) in pending.drain() {
+        p.request.reply(Err(RequestError::Disconnected));
+    }
+    served
+}
+
+// Passes a notification on to the client. A client that does not keep up loses notifications
+// rather than holding up the responses.
+fn notify(session: &mut Session, frame: Frame) {
+    let notification = match Notification::from_frame(frame) {
+        Ok(notification) => notification,
+        Err(e) => {
+            error!("notification ignored: {}", e);
+            return;
+        }
+    };
+    debug!("Device raised {:?}", notification);
+    match session.out_tx.try_send(notification) {
+        Ok(()) if session.dropped_notifications > 0 => {
+            info!("Notification consumer caught up, {} dropped", session.dropped_notifications);
+            session.dropped_notifications = 0;
+        }
+        Ok(()) => (),
+        Err(e) if e.is_full() => {
+            if session.dropped_notifications == 0 {
+                warn!("Notification consumer too slow, dropping notifications until it catches up");
+            }
+            session.dropped_notifications += 1;
+        }
+        Err(_) => (),
+    }
+}
+
+// Sends a request under the next free sequence number and keeps it until its response arrives
+async fn send_request<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    pending: &mut HashMap<u8, PendingRequest>,
+    sequence: &mut u8,
+    req: Request,
+) {
+    if let Err(e) = req.command.check_id() {
+        return req.reply(Err(e.into()));
+    }
+    // Skip sequence numbers still waiting for a response
+    *sequence = sequence.wrapping_add(1);
+    while pending.contains_key(sequence) {
+        *sequence = sequence.wrapping_add(1);
+    }
+    let frame = req.command.clone().into_frame(*sequence);
+    match send_frame(writer, frame).await {
+        Ok(()) => {
+            let deadline = Instant::now() + req.timeout;
+            pending.insert(*sequence, PendingRequest { request: req, deadline });
+        }
+        Err(e) => req.reply(Err(e)),
+    }
+}
+
+// A block announced on the command channel, to be read from the data channel
+struct BlockRead {
+    request: Request,
+    block: RecordingBlock,
+}
+
+// A block must be as long as the read asked for. A raw command does not tell, its block only has
+// to fit in a transfer.
+fn check_block_len(command: &Command, block: &RecordingBlock) -> Result<(), protocol::Error> {
+    match command {
+        Command::ReadRecording { len, .. } if *len != block.len => Err(protocol::Error::BlockLength {
+            requested: *len,
+            announced: block.len,
+        }),
+        _ if block.len > protocol::MAX_BLOCK_LEN => Err(protocol::Error::BlockTooLong(block.len)),
+        _ => Ok(()),
+    }
+}
+
+// Reads the blocks from the data channel in the order they were announced and answers their
+// requests. Ends once the device loop drops the sender.
+async fn read_blocks<D: AsyncRead + AsyncWrite + Unpin>(
+    mut data: D,
+    mut block_rx: mpsc::UnboundedReceiver<BlockRead>,
+) {
+    while let Some(BlockRead { request, mut block }) = block_rx.next().await {
+        let timeout = request.timeout;
+        let res = match tokio::time::timeout(timeout, read_block(&mut data, block.len as usize)).await {
+            Ok(Ok(data)) => {
+                block.data = data;
+                Ok(Response::Block(block))
+            }
+            Ok(Err(e)) => Err(RequestError::Io(e.to_string())),
+            Err(_) => Err(RequestError::Timeout(timeout)),
+        };
+        if res.is_err() {
+            discard_stale(&mut data).await;
+        }
+        request.reply(res);
+    }
+    // Closing releases the reader thread off the executor
+    if let Err(e) = data.close().await {
+        debug!("Closing the data channel failed: {}", e);
+    }
+}
+
+// Reads a block of `len` bytes from the data channel. Zero length packets only end transfers.
+async fn read_block<R: AsyncRead + Unpin>(reader: &mut R, len: usize) -> Result<Vec<u8>, io::Error> {
+    let mut data = vec![0u8; len];
+    let mut filled = 0;
+    while filled < len {
+        filled += reader.read(&mut data[filled..]).await?;
+    }
+    Ok(data)
+}
+
+// Drops what is left of a block that could not be read, so that the next block starts at its
+// first byte
+async fn discard_stale<R: AsyncRead + Unpin>(reader: &mut R) {
+    let mut buf = [0u8; 4096];
+    while let Ok(Ok(len)) = tokio::time::timeout(STALE_DATA_IDLE, reader.read(&mut buf[..])).await {
+        debug!("Discarded {} bytes of stale block data", len);
+    }
+}
+
+async fn send_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: Frame) -> Result<(), RequestError> {
+    let msg = frame.encode()?;
+    // The flush ends the message with a zero length packet if needed
+    writer
+        .write_all(&msg[..])
+        .await
+        .map_err(|e| RequestError::Io(e.to_string()))?;
+    writer.flush().await.map_err(|e| RequestError::Io(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{read_message, Loopback, Pipe};
+    use crate::usb::COMMAND_PACKET_SIZE;
+
+    async fn request(in_tx: &mut mpsc::Sender<Request>, command: Command) -> Reply {
+        let (req, reply_rx) = Request::new(command);
+        in_tx.send(req).await.unwrap();
+        reply_rx.await.unwrap()
+    }
+
+    fn raw(id: u8, payload: Vec<u8>) -> Command {
+        Command::Raw { id, payload }
+    }
+
+    // Answers every command with a response carrying the same payload
+    fn respond(mut device: Pipe) {
+        tokio::spawn(async move {
+            while let Ok(msg) = read_message(&mut device, protocol::frame_len).await {
+                let mut frame = Frame::decode(&msg[..]).unwrap();
+                frame.id |= protocol::RESPONSE_FLAG;
+                device.write_all(&frame.encode().unwrap()).await.unwrap();
+                device.flush().await.unwrap();
             }
+        });
+    }
+
+    fn responding_loopback() -> Loopback {
+        let (host, device) = Pipe::pair(COMMAND_PACKET_SIZE);
+        respond(device);
+        Loopback::with_command(host).0
+    }
+
+    #[tokio::test]
+    async fn loopback_send_receive_close() {
+        let (mut in_tx, mut out_rx, mut control_tx) = spawn_device_loop(responding_loopback(), None);
+
+        assert_eq!(
+            request(&mut in_tx, raw(0x30, vec![0x01, 0x02, 0x03])).await,
+            Ok(Response::Raw {
+                id: 0xb0,
+                payload: vec![0x01, 0x02, 0x03]
+            })
+        );
+        assert_eq!(request(&mut in_tx, Command::Ping).await, Ok(Response::Pong));
+
+        // device_loop drops the Sender once the transport is closed
+        let (close_tx, close_rx) = oneshot::channel();
+        control_tx.send(Control::Close(close_tx)).await.unwrap();
+        assert!(close_rx.await.is_err());
+        assert_eq!(out_rx.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn loopback_messages_around_packet_size() {
+        let (mut in_tx, _out_rx, _control_tx) = spawn_device_loop(responding_loopback(), None);
+
+        // Frame lengths on the wire, header included
+        for &len in &[63, 64, 65, 128] {
+            let payload: Vec<u8> = (0..len - protocol::HEADER_LEN).map(|i| i as u8).collect();
+            assert_eq!(
+                request(&mut in_tx, raw(0x30, payload.clone())).await,
+                Ok(Response::Raw { id: 0xb0, payload })
+            );
+            // Nothing of the previous message may bleed into the next reply
+            assert_eq!(
+                request(&mut in_tx, raw(0x31, vec![0xff])).await,
+                Ok(Response::Raw {
+                    id: 0xb1,
+                    payload: vec![0xff]
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_commands_with_reserved_ids_are_refused() {
+        let (mut in_tx, _out_rx, _control_tx) = spawn_device_loop(responding_loopback(), None);
+        assert_eq!(
+            request(&mut in_tx, raw(0x43, vec![])).await,
+            Err(RequestError::Protocol(protocol::Error::ReservedId(0x43)))
+        );
+    }
+
+    #[tokio::test]
+    async fn responses_out_of_order() {
+        let (host, mut device) = Pipe::pair(COMMAND_PACKET_SIZE);
+        let (loopback, _vis_tx) = Loopback::with_command(host);
+        let (mut in_tx, _out_rx, _control_tx) = spawn_device_loop(loopback, None);
+
+        // Both requests are sent before the first one is answered
+        let (first, first_rx) = Request::new(raw(0x30, vec![1]));
+        let (second, second_rx) = Request::new(raw(0x31, vec![2]));
+        in_tx.send(first).await.unwrap();
+        in_tx.send(second).await.unwrap();
+        let first = read_message(&mut device, protocol::frame_len).await.unwrap();
+        let second = read_message(&mut device, protocol::frame_len).await.unwrap();
+        let first = Frame::decode(&first[..]).unwrap();
+        let second = Frame::decode(&second[..]).unwrap();
+
+        for frame in &mut [second, first] {
+            frame.id |= protocol::RESPONSE_FLAG;
+            device.write_all(&frame.encode().unwrap()).await.unwrap();
+            device.flush().await.unwrap();
+        }
+        assert_eq!(
+            first_rx.await.unwrap(),
+            Ok(Response::Raw {
+                id: 0xb0,
+                payload: vec![1]
+            })
+        );
+        assert_eq!(
+            second_rx.await.unwrap(),
+            Ok(Response::Raw {
+                id: 0xb1,
+                payload: vec![2]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn responses_of_another_command_are_ignored() {
+        let (host, mut device) = Pipe::pair(COMMAND_PACKET_SIZE);
+        let (loopback, _vis_tx) = Loopback::with_command(host);
+        let (mut in_tx, _out_rx, _control_tx) = spawn_device_loop(loopback, None);
+
+        let (req, reply_rx) = Request::new(Command::Ping);
+        in_tx.send(req).await.unwrap();
+        let ping = read_message(&mut device, protocol::frame_len).await.unwrap();
+        let ping = Frame::decode(&ping[..]).unwrap();
+
+        // A stale status response with the same sequence number arrives first
+        let status = Frame {
+            id: 0x02 | protocol::RESPONSE_FLAG,
+            sequence: ping.sequence,
+            payload: vec![0x10, 0x0e, 1],
+        };
+        let pong = Frame {
+            id: ping.id | protocol::RESPONSE_FLAG,
+            sequence: ping.sequence,
+            payload: vec![],
+        };
+        for frame in &[status, pong] {
+            device.write_all(&frame.encode().unwrap()).await.unwrap();
+            device.flush().await.unwrap();
+        }
+        assert_eq!(reply_rx.await.unwrap(), Ok(Response::Pong));
+    }
+
+    #[tokio::test]
+    async fn responses_while_a_block_is_read() {
+        use crate::transport::LOOPBACK_DATA_PACKET_SIZE;
+
+        let (host, mut device) = Pipe::pair(COMMAND_PACKET_SIZE);
+        let (host_data, mut device_data) = Pipe::pair(LOOPBACK_DATA_PACKET_SIZE);
+        let (loopback, _vis_tx) = Loopback::with_pipes(host, host_data);
+        let (mut in_tx, _out_rx, _control_tx) = spawn_device_loop(loopback, None);
+        let answer = |cmd: &Frame, payload: Vec<u8>| {
+            let res = Frame {
+                id: cmd.id | protocol::RESPONSE_FLAG,
+                sequence: cmd.sequence,
+                payload,
+            };
+            res.encode().unwrap()
+        };
+
+        let read = Command::ReadRecording {
+            recording_id: 1,
+            offset: 0,
+            len: 4,
+        };
+        let (req, block_rx) = Request::new(read);
+        in_tx.send(req).await.unwrap();
+        let cmd = read_message(&mut device, protocol::frame_len).await.unwrap();
+        let cmd = Frame::decode(&cmd[..]).unwrap();
+        let payload = vec![1, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0];
+        device.write_all(&answer(&cmd, payload)).await.unwrap();
+        device.flush().await.unwrap();
+
+        // The block has been announced but not sent yet
+        let (req, pong_rx) = Request::new(Command::Ping);
+        in_tx.send(req).await.unwrap();
+        let ping = read_message(&mut device, protocol::frame_len).await.unwrap();
+        let ping = Frame::decode(&ping[..]).unwrap();
+        device.write_all(&answer(&ping, vec![])).await.unwrap();
+        device.flush().await.unwrap();
+        assert_eq!(pong_rx.await.unwrap(), Ok(Response::Pong));
+
+        device_data.write_all(&[1, 2, 3, 4]).await.unwrap();
+        device_data.flush().await.unwrap();
+        match block_rx.await.unwrap() {
+            Ok(Response::Block(block)) => assert_eq!(block.data, vec![1, 2, 3, 4]),
+            res => panic!("unexpected reply {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn blocks_of_another_length_are_refused() {
+        let (host, mut device) = Pipe::pair(COMMAND_PACKET_SIZE);
+        let (loopback, _vis_tx) = Loopback::with_command(host);
+        let (mut in_tx, _out_rx, _control_tx) = spawn_device_loop(loopback, None);
+
+        let read = Command::ReadRecording {
+            recording_id: 1,
+            offset: 0,
+            len: 4,
+        };
+        let (req, block_rx) = Request::new(read);
+        in_tx.send(req).await.unwrap();
+        let cmd = read_message(&mut device, protocol::frame_len).await.unwrap();
+        let cmd = Frame::decode(&cmd[..]).unwrap();
+        // The device announces 4 GiB instead of the 4 bytes asked for
+        let res = Frame {
+            id: cmd.id | protocol::RESPONSE_FLAG,
+            sequence: cmd.sequence,
+            payload: vec![1, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0],
+        };
+        device.write_all(&res.encode().unwrap()).await.unwrap();
+        device.flush().await.unwrap();
+        assert_eq!(
+            block_rx.await.unwrap(),
+            Err(RequestError::Protocol(protocol::Error::BlockLength {
+                requested: 4,
+                announced: u32::MAX
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_dropped() {
+        let (host, mut device) = Pipe::pair(COMMAND_PACKET_SIZE);
+        let (loopback, _vis_tx) = Loopback::with_command(host);
+        let (mut in_tx, _out_rx, _control_tx) = spawn_device_loop(loopback, None);
+
+        let (req, reply_rx) = Request::new(Command::Ping);
+        in_tx.send(req).await.unwrap();
+        let ping = read_message(&mut device, protocol::frame_len).await.unwrap();
+        let ping = Frame::decode(&ping[..]).unwrap();
+
+        // Garbage longer than any frame arrives before the response
+        device.write_all(&[0x55; MAX_MESSAGE_LEN + 100]).await.unwrap();
+        device.flush().await.unwrap();
+        let pong = Frame {
+            id: ping.id | protocol::RESPONSE_FLAG,
+            sequence: ping.sequence,
+            payload: vec![],
+        };
+        device.write_all(&pong.encode().unwrap()).await.unwrap();
+        device.flush().await.unwrap();
+        assert_eq!(reply_rx.await.unwrap(), Ok(Response::Pong));
+
+        // The device is still connected
+        respond(device);
+        assert_eq!(request(&mut in_tx, Command::Ping).await, Ok(Response::Pong));
+    }
+
+    #[tokio::test]
+    async fn notifications_apart_from_responses() {
+        let (host, mut device) = Pipe::pair(COMMAND_PACKET_SIZE);
+        let (loopback, _vis_tx) = Loopback::with_command(host);
+        let (mut in_tx, mut out_rx, _control_tx) = spawn_device_loop(loopback, None);
+
+        let (req, reply_rx) = Request::new(Command::Ping);
+        in_tx.send(req).await.unwrap();
+        let ping = read_message(&mut device, protocol::frame_len).await.unwrap();
+        let ping = Frame::decode(&ping[..]).unwrap();
+
+        // The button is pressed before the device answers, with the same sequence number
+        let button = Frame {
+            id: 0x43,
+            sequence: ping.sequence,
+            payload: vec![],
+        };
+        let pong = Frame {
+            id: ping.id | protocol::RESPONSE_FLAG,
+            sequence: ping.sequence,
+            payload: vec![],
+        };
+        for frame in &[button, pong] {
+            device.write_all(&frame.encode().unwrap()).await.unwrap();
+            device.flush().await.unwrap();
+        }
+        assert_eq!(out_rx.next().await, Some(Notification::EventButton));
+        assert_eq!(reply_rx.await.unwrap(), Ok(Response::Pong));
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        // The device end is kept, but never answers
+        let (host, _device) = Pipe::pair(COMMAND_PACKET_SIZE);
+        let (loopback, _vis_tx) = Loopback::with_command(host);
+        let (mut in_tx, _out_rx, _control_tx) = spawn_device_loop(loopback, None);
+
+        let timeout = Duration::from_millis(20);
+        let (req, reply_rx) = Request::with_timeout(Command::Ping, timeout);
+        in_tx.send(req).await.unwrap();
+        assert_eq!(reply_rx.await.unwrap(), Err(RequestError::Timeout(timeout)));
+    }
+
+    #[tokio::test]
+    async fn requests_fail_while_suspended() {
+        use crate::deviceinfo::{FirmwareVersion, Speed};
+
+        let info = DeviceInfo {
+            id: "SN1".to_string(),
+            path: Some("1:2".to_string()),
+            port_chain: vec![1],
+            speed: Speed::High,
+            vendor_id: VID,
+            product_id: PID,
+            firmware_version: FirmwareVersion {
+                major: 1,
+                minor: 0,
+                sub_minor: 0,
+            },
+            manufacturer: None,
+            product: None,
+            serial: Some("SN1".to_string()),
+            state: AcquisitionState::Available,
+            last_seen: SystemTime::now(),
+        };
+        let (events, _) = broadcast::channel(EVENT_QUEUE_LEN);
+        let mut entry = DeviceEntry::new(info, events);
+        let (mut in_tx, _out_rx, control_tx) = spawn_device_loop(responding_loopback(), None);
+        entry.acquire(control_tx);
+
+        // The device leaves, its client is told right away
+        let session = take_session(entry.detaching().unwrap()).await;
+        entry.suspend(session);
+        assert!(entry.has_live_session());
+        assert_eq!(request(&mut in_tx, Command::Ping).await, Err(RequestError::Disconnected));
+
+        // and is served again once it is back
+        let session = take_session(entry.resuming().unwrap()).await.unwrap();
+        entry.acquire(spawn_session_loop(responding_loopback(), session));
+        assert_eq!(request(&mut in_tx, Command::Ping).await, Ok(Response::Pong));
+    }
+
+    #[tokio::test]
+    async fn live_stream_counts_lost_packets() {
+        use crate::deviceinfo::FirmwareVersion;
+        use crate::stream::{Gap, StreamItem};
+        use crate::usbfutures::VisFrame;
+
+        let firmware = FirmwareVersion {
+            major: 1,
+            minor: 0,
+            sub_minor: 0,
+        };
+        let (samples_tx, samples_rx) = broadcast::channel(SAMPLE_QUEUE_LEN);
+        let mut subscription = SampleSubscription::new(samples_rx);
+        let counters = Arc::new(StreamCounters::default());
+        let decoder = SampleDecoder::new(firmware).unwrap();
+        let live = LiveStream::new(decoder, Default::default(), Arc::clone(&counters), samples_tx);
+        let (loopback, vis_tx) = Loopback::new();
+        let (_in_tx, _out_rx, _control_tx) = spawn_device_loop(loopback, Some(live));
+
+        let packet = |counter: u16| {
+            let mut data = vec![0u8; 64];
+            data[..2].copy_from_slice(&counter.to_le_bytes());
+            VisFrame {
+                timestamp: SystemTime::now(),
+                data,
+            }
+        };
+        for frame in [packet(1), packet(2), packet(5), packet(5)].iter().cloned() {
+            vis_tx.unbounded_send(frame).unwrap();
+        }
+        vis_tx
+            .unbounded_send(VisFrame {
+                timestamp: SystemTime::now(),
+                data: vec![0; 10],
+            })
+            .unwrap();
+
+        let counter = |item: Option<StreamItem>| match item {
+            Some(StreamItem::Block(block)) => block.counter,
+            item => panic!("expected a block, got {:?}", item),
+        };
+        assert_eq!(counter(subscription.next().await), 1);
+        assert_eq!(counter(subscription.next().await), 2);
+        assert_eq!(
+            subscription.next().await,
+            Some(StreamItem::Gap(Gap {
+                after_counter: 2,
+                lost_packets: 2
+            }))
+        );
+        assert_eq!(counter(subscription.next().await), 5);
+        // The invalid packet is the last one, it has been counted once the stats show it
+        while counters.stats().invalid_packets == 0 {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
         }
+        let stats = counters.stats();
+        assert_eq!(stats.received_packets, 4);
+        assert_eq!(stats.lost_packets, 2);
+        assert_eq!(stats.gaps, 1);
     }
 }
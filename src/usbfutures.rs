@@ -1,12 +1,16 @@
 
+use futures::channel::mpsc as async_mpsc;
 use futures::prelude::*;
 use futures::task::SpawnError;
 use libusb::DeviceHandle;
 use std::io;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
 use std::task::{Context, Poll, Waker};
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
 
@@ -20,380 +24,659 @@ pub enum Error {
     Spawn(#[from] SpawnError),
 }
 
-enum ReadState {
-    Idle,
-    Busy,
+// Number of writes queued for the writer thread before poll_write returns Pending
+const WRITE_QUEUE_LEN: usize = 16;
+
+#[derive(Default)]
+struct WriteState {
+    in_flight: usize,
+    error: Option<libusb::Error>,
+    flush_waker: Option<Waker>,
 }
 
-struct DeviceInner {
-    device: Arc<DeviceHandle<'static>>,
+// Writes to a bulk OUT endpoint from a dedicated thread so that poll_write never blocks the
+// executor. Every accepted write is sent as one bulk transfer of at most `max_len` bytes. A flush
+// ends the message: if the last transfer ended on a packet boundary a zero length packet follows
+// so that the device sees where the message ends.
+struct BulkWriter {
+    write_tx: Option<async_mpsc::Sender<Vec<u8>>>,
+    write_thread: Option<std::thread::JoinHandle<()>>,
+    state: Arc<Mutex<WriteState>>,
+    max_len: usize,
+    packet_size: usize,
+    zlp_pending: bool,
+}
+
+impl BulkWriter {
+    fn new(
+        device: Arc<DeviceHandle<'static>>,
+        endpoint: u8,
+        max_len: usize,
+        packet_size: usize,
+        timeout: Duration,
+    ) -> Self {
+        let (write_tx, mut write_rx) = async_mpsc::channel::<Vec<u8>>(WRITE_QUEUE_LEN);
+        let state: Arc<Mutex<WriteState>> = Default::default();
+        let jh = std::thread::spawn({
+            let state = Arc::clone(&state);
+            move || {
+                // Ends once all senders are dropped and the queue is drained
+                while let Some(buf) = futures::executor::block_on(write_rx.next()) {
+                    let res = device.write_bulk(endpoint, &buf[..], timeout);
+                    debug!("Wrote to {:#x}: {:?}", endpoint, &buf[..]);
+                    let mut state = match state.lock() {
+                        Ok(state) => state,
+                        Err(_) => return,
+                    };
+                    state.in_flight -= 1;
+                    if let Err(e) = res {
+                        error!("libusb failed writing to {:#x}: {}", endpoint, e);
+                        state.error = Some(e);
+                    }
+                    if let Some(waker) = state.flush_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+        });
+        BulkWriter {
+            write_tx: Some(write_tx),
+            write_thread: Some(jh),
+            state,
+            max_len,
+            packet_size: usize::max(packet_size, 1),
+            zlp_pending: false,
+        }
+    }
+
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let len = usize::min(self.max_len, buf.len());
+        match self.poll_send(cx, buf[..len].to_vec()) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        self.zlp_pending = len.is_multiple_of(self.packet_size);
+        Poll::Ready(Ok(len))
+    }
+
+    // Queues one transfer for the writer thread
+    fn poll_send(&mut self, cx: &mut Context, transfer: Vec<u8>) -> Poll<Result<(), io::Error>> {
+        self.check_error()?;
+        let write_tx = match self.write_tx.as_mut() {
+            Some(write_tx) => write_tx,
+            None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        };
+        // Pending while the queue is full, the writer thread wakes us once there is room
+        match write_tx.poll_ready(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            Poll::Pending => return Poll::Pending,
+        }
+        lock_write_state(&self.state)?.in_flight += 1;
+        if write_tx.start_send(transfer).is_err() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // Ready once every accepted write, and the zero length packet ending the message, has been
+    // handed to libusb
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        if self.zlp_pending {
+            match self.poll_send(cx, Vec::new()) {
+                Poll::Ready(Ok(())) => self.zlp_pending = false,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let mut state = lock_write_state(&self.state)?;
+        if state.in_flight > 0 {
+            state.flush_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        drop(state);
+        self.check_error()?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn check_error(&self) -> Result<(), io::Error> {
+        match lock_write_state(&self.state)?.error.take() {
+            Some(e) => Err(io::Error::other(format!("libusb failed: {}", e))),
+            None => Ok(()),
+        }
+    }
+
+    // Lets the writer thread drain the queue and end
+    fn stop(&mut self) -> Option<JoinHandle<()>> {
+        drop(self.write_tx.take());
+        self.write_thread.take()
+    }
+}
+
+fn lock_write_state(state: &Mutex<WriteState>) -> Result<std::sync::MutexGuard<'_, WriteState>, io::Error> {
+    state.lock().map_err(|e| {
+        io::Error::other(format!("Mutex broken: {:?}", e))
+    })
+}
+
+// Number of completed transfers buffered for the consumer before the reader thread waits
+const READ_QUEUE_LEN: usize = 16;
+
+// Reads from a bulk IN endpoint continuously on a dedicated thread, so poll_read no longer has
+// to request every transfer. Each transfer fills a buffer of `transfer_len` bytes taken from a
+// pool that buffers are returned to once poll_read has handed out their contents. poll_read
+// never hands out bytes of two transfers at once, and returns Ok(0) for a zero length packet.
+struct BulkReader {
     read_thread: Option<std::thread::JoinHandle<()>>,
-    rstate: ReadState,
-    data_rx: mpsc::Receiver<Option<[u8; 64]>>, // One message per read
-    req_tx: Option<mpsc::Sender<Waker>>,       // One message per expected read
-    buffer: Option<[u8; 64]>,
+    running: Arc<AtomicBool>,
+    data_rx: async_mpsc::Receiver<Vec<u8>>, // One message per transfer
+    pool_tx: mpsc::Sender<Vec<u8>>,
+    buffer: Option<Vec<u8>>,
     buffer_pos: usize,
 }
 
-// Proxy object to implement more than one AsyncRead trait
-pub struct VisInner {
+impl BulkReader {
+    fn new(device: Arc<DeviceHandle<'static>>, endpoint: u8, transfer_len: usize, timeout: Duration) -> Self {
+        let (data_tx, data_rx) = async_mpsc::channel(READ_QUEUE_LEN);
+        let (pool_tx, pool_rx) = mpsc::channel();
+        let running = Arc::new(AtomicBool::new(true));
+        let jh = std::thread::spawn({
+            let running = Arc::clone(&running);
+            move || bulk_read_loop(device, endpoint, transfer_len, timeout, running, pool_rx, data_tx)
+        });
+        BulkReader {
+            read_thread: Some(jh),
+            running,
+            data_rx,
+            pool_tx,
+            buffer: None,
+            buffer_pos: 0,
+        }
+    }
+
+    // Hands out bytes left over from the previous transfer first. Bytes that do not fit in `buf`
+    // are kept for the next call.
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<usize, io::Error>> {
+        let inner_buf = match self.buffer.take() {
+            Some(inner_buf) => inner_buf,
+            None => match self.data_rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(vec)) => {
+                    debug!("Read data {:?}", &vec[..]);
+                    self.buffer_pos = 0;
+                    vec
+                }
+                Poll::Ready(None) => {
+                    return Poll::Ready(Err(io::Error::other("Inner channel dead")))
+                }
+                Poll::Pending => return Poll::Pending,
+            },
+        };
+        let len = usize::min(buf.len(), inner_buf.len() - self.buffer_pos);
+        buf[..len].copy_from_slice(&inner_buf[self.buffer_pos..self.buffer_pos + len]);
+        if self.buffer_pos + len < inner_buf.len() {
+            self.buffer = Some(inner_buf);
+            self.buffer_pos += len;
+        } else {
+            // The reader thread is gone if this fails, the buffer is simply dropped then
+            let _ = self.pool_tx.send(inner_buf);
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn stop(&mut self) -> Option<JoinHandle<()>> {
+        // The reader thread checks the flag every time a read times out and stops once the
+        // channel is closed, in case it is waiting for room in the queue
+        self.running.store(false, Ordering::Release);
+        self.data_rx.close();
+        self.read_thread.take()
+    }
+}
+
+// Keeps a transfer going on `endpoint` at all times. A read only waits for the consumer when
+// READ_QUEUE_LEN transfers are already queued.
+fn bulk_read_loop(
     device: Arc<DeviceHandle<'static>>,
+    endpoint: u8,
+    transfer_len: usize,
+    timeout: Duration,
+    running: Arc<AtomicBool>,
+    pool_rx: mpsc::Receiver<Vec<u8>>,
+    mut data_tx: async_mpsc::Sender<Vec<u8>>,
+) {
+    let mut buf = Vec::new();
+    while running.load(Ordering::Acquire) {
+        if buf.is_empty() {
+            buf = pool_rx.try_recv().unwrap_or_default();
+        }
+        buf.resize(transfer_len, 0);
+        match device.read_bulk(endpoint, &mut buf[..], timeout) {
+            // Nothing arrived, the buffer is used for the next read
+            Err(libusb::Error::Timeout) => continue,
+            // A zero length packet is passed on as an empty transfer, it ends a message
+            Ok(len) => {
+                debug!("Read {} bytes from {:#x}", len, endpoint);
+                buf.truncate(len);
+                let filled = std::mem::take(&mut buf);
+                if futures::executor::block_on(data_tx.send(filled)).is_err() {
+                    info!("Reader of {:#x} dropped, shutting down", endpoint);
+                    return;
+                }
+            }
+            Err(e) => {
+                error!("libusb failed reading from {:#x}: {}", endpoint, e);
+                return;
+            }
+        }
+    }
+    info!("Reader of {:#x} stopped", endpoint);
+}
+
+// A channel of the device with threads of its own. The threads are stopped once the last clone
+// of the device lets go of the channel. `Device::shutdown` waits for them, otherwise they are
+// joined on the blocking pool, as joining may have to wait for a read to time out.
+trait Threads {
+    // Tells the threads to stop and returns them to be joined
+    fn stop(&mut self) -> Vec<JoinHandle<()>>;
+}
+
+fn join(threads: Vec<JoinHandle<()>>) {
+    for jh in threads {
+        if jh.join().is_err() {
+            error!("failed to join a channel thread");
+        }
+    }
+}
+
+// Joins the threads of a channel that was dropped, off the executor if there is one
+fn join_later(threads: Vec<JoinHandle<()>>) {
+    if threads.is_empty() {
+        return;
+    }
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => drop(runtime.spawn_blocking(move || join(threads))),
+        Err(_) => join(threads),
+    }
+}
+
+struct DeviceInner {
+    writer: BulkWriter,
+    reader: BulkReader,
+}
+
+impl Threads for DeviceInner {
+    fn stop(&mut self) -> Vec<JoinHandle<()>> {
+        self.reader.stop().into_iter().chain(self.writer.stop()).collect()
+    }
+}
+
+impl Drop for DeviceInner {
+    fn drop(&mut self) {
+        debug!("dropping libusb connection");
+        join_later(self.stop());
+    }
+}
+
+// Number of visualization frames buffered before the reader thread starts dropping them
+const VIS_QUEUE_LEN: usize = 256;
+
+/// One packet read from the visualization endpoint, stamped with the time it arrived.
+#[derive(Debug, Clone)]
+pub struct VisFrame {
+    pub timestamp: SystemTime,
+    pub data: Vec<u8>,
+}
+
+struct VisInner {
     read_thread: Option<std::thread::JoinHandle<()>>,
-    rstate: ReadState,
-    data_rx: mpsc::Receiver<Option<[u8; 64]>>, // One message per read
-    req_tx: Option<mpsc::Sender<Waker>>,       // One message per expected read
-    buffer: Option<[u8; 64]>,
-    buffer_pos: usize,
+    running: Arc<AtomicBool>,
+    data_rx: async_mpsc::Receiver<VisFrame>,
+}
+
+impl Threads for VisInner {
+    fn stop(&mut self) -> Vec<JoinHandle<()>> {
+        // The reader thread checks this flag every time a read times out
+        self.running.store(false, Ordering::Release);
+        self.read_thread.take().into_iter().collect()
+    }
+}
+
+impl Drop for VisInner {
+    fn drop(&mut self) {
+        join_later(self.stop());
+    }
 }
 
+// Proxy object so that the visualization endpoint can be consumed independently of the command
+// channel
 pub struct VisProxy {
     inner: Option<Arc<Mutex<VisInner>>>,
 }
 
+// Preferred size of one transfer on the bulk data endpoints. Rounded down to a multiple of the
+// endpoint's max packet size so that a read never ends in the middle of a packet.
+const DATA_TRANSFER_SIZE: usize = 16 * 1024;
+
+struct DataInner {
+    writer: BulkWriter,
+    reader: BulkReader,
+}
+
+impl Threads for DataInner {
+    fn stop(&mut self) -> Vec<JoinHandle<()>> {
+        self.reader.stop().into_iter().chain(self.writer.stop()).collect()
+    }
+}
+
+impl Drop for DataInner {
+    fn drop(&mut self) {
+        join_later(self.stop());
+    }
+}
+
+// Bulk channel on EP_DATA_OUT/EP_DATA_IN used for large transfers such as recordings. It has its
+// own reader thread so that it never blocks the command channel.
+pub struct DataChannel {
+    inner: Option<Arc<Mutex<DataInner>>>,
+}
+
+/// A monitor opened with libusb. Clones share the reader and writer threads, which stop once the
+/// last clone is dropped.
 pub struct Device {
     // store an Option so that `close` works
     inner: Option<Arc<Mutex<DeviceInner>>>,
     pub vis: VisProxy,
+    pub data: DataChannel,
 }
 
 impl Clone for Device {
     fn clone(&self) -> Self {
         Device {
-            inner: self.inner.as_ref().map(|dev| Arc::clone(&dev)),
+            inner: self.inner.as_ref().map(Arc::clone),
             vis : VisProxy {
-                inner: self.vis.inner.as_ref().map(|dev| Arc::clone(&dev)),
-            }
+                inner: self.vis.inner.as_ref().map(Arc::clone),
+            },
+            data: DataChannel {
+                inner: self.data.inner.as_ref().map(Arc::clone),
+            },
         }
     }
 }
 
-impl Drop for Device {
-    fn drop(&mut self) {
-        debug!("dropping libusb connection");
-        if let Some(inner) = self.inner.take() {
-            if let Ok(mut guard) = inner.lock() {
-                // Take the waker queue and drop it so that the reader thread finihes
-                let req_tx = guard.req_tx.take();
-                drop(req_tx);
-
-                // Wait for the reader thread to finish
-                match guard.read_thread.take() {
-                    Some(jh) => match jh.join() {
-                        Ok(_) => info!("device read thread joined"),
-                        Err(_) => error!("failed to join device read thread"),
-                    },
-                    None => error!("already joined"),
-                }
-            } else {
-                error!("Failed to take lock on device");
-            }
-        } else {
-            error!("there was no inner");
+impl VisProxy {
+    fn new(device: Arc<DeviceHandle<'static>>) -> Self {
+        let (data_tx, data_rx) = async_mpsc::channel(VIS_QUEUE_LEN);
+        let running = Arc::new(AtomicBool::new(true));
+        let jh = std::thread::spawn({
+            let running = Arc::clone(&running);
+            move || vis_read_loop(device, running, data_tx)
+        });
+        VisProxy {
+            inner: Some(Arc::new(Mutex::new(VisInner {
+                read_thread: Some(jh),
+                running,
+                data_rx,
+            }))),
         }
-        //TODO: VisProxy
+    }
+
+    // A proxy without a reader thread, whose stream has ended
+    pub(crate) fn closed() -> Self {
+        VisProxy { inner: None }
     }
 }
 
-impl Device { pub fn new(device: DeviceHandle<'static>) -> Result<Self, Error> {
-        let (data_tx, data_rx) = mpsc::channel();
-        let (req_tx, req_rx) = mpsc::channel::<Waker>();
+// Failed reads in a row after which the visualization endpoint is given up
+const VIS_MAX_ERRORS: u32 = 20;
 
-        // Must be accessed from both inner thread and asyn_write
-        let device = Arc::new(device);
-        let jh = std::thread::spawn({
-            let device = Arc::clone(&device);
-            move || {
-                loop {
-                    // Wait for read request
-                    debug!("waiting for request");
-                    let waker = match req_rx.recv() {
-                        Ok(waker) => waker,
-                        Err(_e) => {
-                            info!("No more wakers, shutting down");
-                            return;
-                        }
-                    };
-                    debug!("Got notified");
-                    {
-                        let mut buf = [0u8; 64];
-                        match device.read_bulk(crate::usb::EP_IN, &mut buf[..], std::time::Duration::from_millis(200)) {
-                            Err(e) => {
-                                error!("libusb failed: {}", e);
-                                drop(data_tx);
-                                waker.wake_by_ref();
-                                break;
-                            }
-                            Ok(len) => {
-                                if len == 0 {
-                                    data_tx.send(None).unwrap();
-                                    waker.wake_by_ref();
-                                    continue;
-                                }
-                                debug!("Read data");
-                                if let Err(e) = data_tx.send(Some(buf)) {
-                                    error!("Sending internally: {}", e);
-                                    break;
-                                }
-                                waker.wake_by_ref();
-                            }
-                        }
+// Reads EP_VIS continuously. The endpoint streams on its own so, unlike the command channel,
+// there are no read requests; frames are dropped when the consumer does not keep up. A failed
+// read loses a packet, which the consumer notices from the packet counter, so the loop only
+// stops once the device is gone or reads keep failing.
+fn vis_read_loop(
+    device: Arc<DeviceHandle<'static>>,
+    running: Arc<AtomicBool>,
+    mut data_tx: async_mpsc::Sender<VisFrame>,
+) {
+    let mut buf = [0u8; 64];
+    let mut errors = 0;
+    while running.load(Ordering::Acquire) {
+        match device.read_bulk(crate::usb::EP_VIS, &mut buf[..], Duration::from_millis(200)) {
+            Ok(0) | Err(libusb::Error::Timeout) => continue,
+            Ok(len) => {
+                errors = 0;
+                let frame = VisFrame {
+                    timestamp: SystemTime::now(),
+                    data: buf[..len].to_vec(),
+                };
+                if let Err(e) = data_tx.try_send(frame) {
+                    if e.is_disconnected() {
+                        info!("Visualization stream dropped, shutting down");
+                        return;
                     }
+                    warn!("Visualization consumer too slow, frame dropped");
                 }
             }
-        });
+            Err(libusb::Error::NoDevice) => {
+                info!("Device gone, visualization stream closed");
+                return;
+            }
+            Err(e) if errors < VIS_MAX_ERRORS => {
+                errors += 1;
+                warn!("libusb failed on visualization endpoint: {}, retrying", e);
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => {
+                error!("libusb failed on visualization endpoint: {}", e);
+                return;
+            }
+        }
+    }
+    info!("Visualization stream closed, shutting down");
+}
+
+impl DataChannel {
+    // A channel without a reader thread, reading from it fails
+    pub(crate) fn closed() -> Self {
+        DataChannel { inner: None }
+    }
+
+    fn new(device: Arc<DeviceHandle<'static>>, max_packet_size: usize) -> Self {
+        let max_packet_size = usize::max(max_packet_size, 1);
+        let transfer_len = usize::max(max_packet_size, DATA_TRANSFER_SIZE / max_packet_size * max_packet_size);
+        DataChannel {
+            inner: Some(Arc::new(Mutex::new(DataInner {
+                // The device may take a while before it starts sending a recording, so reads
+                // keep being retried until data arrives
+                reader: BulkReader::new(
+                    Arc::clone(&device),
+                    crate::usb::EP_DATA_IN,
+                    transfer_len,
+                    Duration::from_millis(1000),
+                ),
+                writer: BulkWriter::new(
+                    device,
+                    crate::usb::EP_DATA_OUT,
+                    transfer_len,
+                    max_packet_size,
+                    Duration::from_millis(1000),
+                ),
+            }))),
+        }
+    }
+}
+
+impl Device { pub fn new(device: DeviceHandle<'static>, data_max_packet_size: usize) -> Result<Self, Error> {
+        // Must be accessed from both the reader and writer threads
+        let device = Arc::new(device);
         Ok(Device {
             inner: Some(Arc::new(Mutex::new(DeviceInner {
-                device: Arc::clone(&device),
-                read_thread: Some(jh),
-                rstate: ReadState::Idle,
-                data_rx,
-                req_tx: Some(req_tx),
-                buffer: None,
-                buffer_pos: 0,
+                // One transfer per packet, so that a short packet ending a response is never
+                // merged with the start of the next one
+                reader: BulkReader::new(
+                    Arc::clone(&device),
+                    crate::usb::EP_IN,
+                    crate::usb::COMMAND_PACKET_SIZE,
+                    Duration::from_millis(200),
+                ),
+                writer: BulkWriter::new(
+                    Arc::clone(&device),
+                    crate::usb::EP_OUT,
+                    crate::usb::COMMAND_PACKET_SIZE,
+                    crate::usb::COMMAND_PACKET_SIZE,
+                    Duration::from_millis(200),
+                ),
             }))),
-            vis : VisProxy {
-                inner: Some(Arc::new(Mutex::new(VisInner {
-                    device,
-                    read_thread: None,
-                    rstate: ReadState::Idle,
-                    data_rx : todo!(),
-                    req_tx: None,
-                    buffer: None,
-                    buffer_pos: 0,
-                }))),
-            }
+            vis: VisProxy::new(Arc::clone(&device)),
+            data: DataChannel::new(device, data_max_packet_size),
         })
     }
+
+    /// Lets go of all channels and waits for the threads of those no clone uses any more. Blocks
+    /// until pending reads time out, so it belongs on the blocking pool.
+    pub fn shutdown(mut self) {
+        shutdown_last(self.inner.take());
+        shutdown_last(self.vis.inner.take());
+        shutdown_last(self.data.inner.take());
+    }
+}
+
+// Stops the threads of a channel and waits for them if this is its last reference
+fn shutdown_last<T: Threads>(inner: Option<Arc<Mutex<T>>>) {
+    if let Some(Ok(inner)) = inner.map(Arc::try_unwrap) {
+        join(inner.into_inner().unwrap_or_else(PoisonError::into_inner).stop());
+    }
 }
 
 impl AsyncWrite for Device {
     fn poll_write(
         mut self: Pin<&mut Self>,
-        _cx: &mut Context,
-        mut buf: &[u8],
+        cx: &mut Context,
+        buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        let len = buf.len();
-        if self.inner.is_none() {
-            return Poll::Ready(Err(io::Error::new(
+        match self.inner.as_mut() {
+            Some(inner) => inner
+                .lock()
+                .map_err(|e| io::Error::other(format!("Mutex broken: {:?}", e)))?
+                .writer
+                .poll_write(cx, buf),
+            None => Poll::Ready(Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Cannot poll a closed device",
-            )));
-        }
-        loop {
-            let max_len = usize::min(64, buf.len());
-            debug!("Will write {:?}", &buf[..max_len]);
-            match self.inner.as_mut().unwrap().lock() {
-                Ok(guard) => {
-                    guard.device
-                        .write_bulk(crate::usb::EP_OUT, &buf[..max_len], std::time::Duration::from_millis(200))
-                        .map_err(|_| io::Error::new(io::ErrorKind::Other, "libusb failed"))?;
-                    debug!("Wrote: {:?}", &buf[0..max_len]);
-                },
-                Err(e) => {
-                    return Poll::Ready(Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Mutex broken: {:?}", e),
-                    )))
-                }
-            }
-            buf = &buf[max_len..];
-            if buf.len() == 0 {
-                debug!("Wrote total {}: {:?}", buf.len(), buf);
-                return Poll::Ready(Ok(len));
-            }
+            ))),
         }
     }
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
-        Poll::Ready(Ok(()))
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        match self.inner.as_mut() {
+            Some(inner) => inner
+                .lock()
+                .map_err(|e| io::Error::other(format!("Mutex broken: {:?}", e)))?
+                .writer
+                .poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
     }
-    // TODO cleanup read thread...
+    // Lets go of the command channel. Its threads stop once no clone of the device uses it.
     fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
-        let this: &mut Self = &mut self;
-        // take the device and drop it
-        let _device = this.inner.take();
+        self.inner = None;
         Poll::Ready(Ok(()))
     }
 }
 
-// Will always read out 64 bytes. Make sure to read out all bytes to avoid trailing bytes in next
-// readout.
-// Will store all bytes that did not fit in provided buffer and give them next time.
+// Hands out the responses in the order they were read. Bytes that do not fit in the provided
+// buffer are given next time, so make sure to read out all bytes to avoid trailing bytes in the
+// next readout.
 impl AsyncRead for Device {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context,
         buf: &mut [u8],
     ) -> Poll<Result<usize, io::Error>> {
-        if self.inner.is_none() {
-            return Poll::Ready(Err(io::Error::new(
+        match self.inner.as_mut() {
+            Some(inner) => inner
+                .lock()
+                .map_err(|e| io::Error::other(format!("Mutex broken: {:?}", e)))?
+                .reader
+                .poll_read(cx, buf),
+            None => Poll::Ready(Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Cannot poll a closed device",
-            )));
+            ))),
         }
-        let mut this =
-            self.inner.as_mut().unwrap().lock().map_err(|e| {
-                io::Error::new(io::ErrorKind::Other, format!("Mutex broken: {:?}", e))
-            })?;
-        loop {
-            let waker = cx.waker().clone();
-            match this.rstate {
-                ReadState::Idle => {
-                    debug!("Sending waker");
-                    if let Some(req_tx) = &mut this.req_tx {
-                        if let Err(_e) = req_tx.send(waker) {
-                            error!("failed to send waker");
-                        }
-                    } else {
-                        return Poll::Ready(Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Failed internal send",
-                        )));
-                    }
-                    this.rstate = ReadState::Busy;
-                }
-                ReadState::Busy => {
-                    // First send any bytes from the previous readout
-                    if let Some(inner_buf) = this.buffer.take() {
-                        let len = usize::min(buf.len(), inner_buf.len());
-                        let inner_slice = &inner_buf[this.buffer_pos..this.buffer_pos + len];
-                        let buf_slice = &mut buf[..len];
-                        buf_slice.copy_from_slice(inner_slice);
-                        // Check if there is more data left
-                        if this.buffer_pos + inner_slice.len() < inner_buf.len() {
-                            this.buffer = Some(inner_buf);
-                            this.buffer_pos += inner_slice.len();
-                        } else {
-                            this.rstate = ReadState::Idle;
-                        }
-                        return Poll::Ready(Ok(len));
-                    }
+    }
+}
 
-                    // Second try to receive more bytes
-                    let vec = match this.data_rx.try_recv() {
-                        Ok(Some(vec)) => vec,
-                        Ok(None) => {
-                            // end of stream?
-                            return Poll::Pending;
-                        }
-                        Err(e) => match e {
-                            mpsc::TryRecvError::Disconnected => {
-                                return Poll::Ready(Err(io::Error::new(
-                                    io::ErrorKind::Other,
-                                    format!("Inner channel dead"),
-                                )));
-                            }
-                            mpsc::TryRecvError::Empty => {
-                                return Poll::Pending;
-                            }
-                        },
-                    };
-                    debug!("Read data {:?}", &vec[..]);
-                    let len = usize::min(vec.len(), buf.len());
-                    let buf_slice = &mut buf[..len];
-                    let vec_slice = &vec[..len];
-                    buf_slice.copy_from_slice(vec_slice);
-                    if len < vec.len() {
-                        // If bytes did not fit in buf, store bytes for next readout
-                        this.buffer = Some(vec);
-                        this.buffer_pos = 0;
-                    } else {
-                        this.rstate = ReadState::Idle;
-                    }
-                    debug!("returning {}", len);
-                    return Poll::Ready(Ok(len));
-                }
-            };
+impl Stream for VisProxy {
+    type Item = VisFrame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<VisFrame>> {
+        let inner = match self.inner.as_mut() {
+            Some(inner) => inner,
+            None => return Poll::Ready(None),
+        };
+        match inner.lock() {
+            Ok(mut guard) => guard.data_rx.poll_next_unpin(cx),
+            Err(e) => {
+                error!("Mutex broken: {:?}", e);
+                Poll::Ready(None)
+            }
         }
     }
 }
 
-impl AsyncRead for VisProxy {
-    fn poll_read(
+impl AsyncWrite for DataChannel {
+    fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context,
-        buf: &mut [u8],
+        buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        if self.inner.is_none() {
-            return Poll::Ready(Err(io::Error::new(
+        match self.inner.as_mut() {
+            Some(inner) => inner
+                .lock()
+                .map_err(|e| io::Error::other(format!("Mutex broken: {:?}", e)))?
+                .writer
+                .poll_write(cx, buf),
+            None => Poll::Ready(Err(io::Error::new(
                 io::ErrorKind::InvalidData,
-                "Cannot poll a closed device",
-            )));
+                "Cannot poll a closed data channel",
+            ))),
         }
-        let mut this =
-            self.inner.as_mut().unwrap().lock().map_err(|e| {
-                io::Error::new(io::ErrorKind::Other, format!("Mutex broken: {:?}", e))
-            })?;
-        loop {
-            let waker = cx.waker().clone();
-            match this.rstate {
-                ReadState::Idle => {
-                    debug!("Sending waker");
-                    if let Some(req_tx) = &mut this.req_tx {
-                        if let Err(_e) = req_tx.send(waker) {
-                            error!("failed to send waker");
-                        }
-                    } else {
-                        return Poll::Ready(Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Failed internal send",
-                        )));
-                    }
-                    this.rstate = ReadState::Busy;
-                }
-                ReadState::Busy => {
-                    // First send any bytes from the previous readout
-                    if let Some(inner_buf) = this.buffer.take() {
-                        let len = usize::min(buf.len(), inner_buf.len());
-                        let inner_slice = &inner_buf[this.buffer_pos..this.buffer_pos + len];
-                        let buf_slice = &mut buf[..len];
-                        buf_slice.copy_from_slice(inner_slice);
-                        // Check if there is more data left
-                        if this.buffer_pos + inner_slice.len() < inner_buf.len() {
-                            this.buffer = Some(inner_buf);
-                            this.buffer_pos += inner_slice.len();
-                        } else {
-                            this.rstate = ReadState::Idle;
-                        }
-                        return Poll::Ready(Ok(len));
-                    }
-
-                    // Second try to receive more bytes
-                    let vec = match this.data_rx.try_recv() {
-                        Ok(Some(vec)) => vec,
-                        Ok(None) => {
-                            // end of stream?
-                            return Poll::Pending;
-                        }
-                        Err(e) => match e {
-                            mpsc::TryRecvError::Disconnected => {
-                                return Poll::Ready(Err(io::Error::new(
-                                    io::ErrorKind::Other,
-                                    format!("Inner channel dead"),
-                                )));
-                            }
-                            mpsc::TryRecvError::Empty => {
-                                return Poll::Pending;
-                            }
-                        },
-                    };
-                    debug!("Read data {:?}", &vec[..]);
-                    let len = usize::min(vec.len(), buf.len());
-                    let buf_slice = &mut buf[..len];
-                    let vec_slice = &vec[..len];
-                    buf_slice.copy_from_slice(vec_slice);
-                    if len < vec.len() {
-                        // If bytes did not fit in buf, store bytes for next readout
-                        this.buffer = Some(vec);
-                        this.buffer_pos = 0;
-                    } else {
-                        this.rstate = ReadState::Idle;
-                    }
-                    debug!("returning {}", len);
-                    return Poll::Ready(Ok(len));
-                }
-            };
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        match self.inner.as_mut() {
+            Some(inner) => inner
+                .lock()
+                .map_err(|e| io::Error::other(format!("Mutex broken: {:?}", e)))?
+                .writer
+                .poll_flush(cx),
+            None => Poll::Ready(Ok(())),
         }
     }
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        self.inner = None;
+        Poll::Ready(Ok(()))
+    }
 }
 
-
+impl AsyncRead for DataChannel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        match self.inner.as_mut() {
+            Some(inner) => inner
+                .lock()
+                .map_err(|e| io::Error::other(format!("Mutex broken: {:?}", e)))?
+                .reader
+                .poll_read(cx, buf),
+            None => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Cannot poll a closed data channel",
+            ))),
+        }
+    }
+}
@@ -0,0 +1,1178 @@
+use crate::calibration::{self, CalibrationError};
+use crate::client::{ClientError, HolterClient};
+use crate::clock;
+use crate::config::{self, ConfigDiff, DeviceConfig, Profile};
+use crate::download::{Download, DownloadProgress};
+use crate::filter::{FilterChain, FilterSpec};
+use crate::protocol::{Command, Notification, Response};
+use crate::samples::{SampleBlock, SampleUnit};
+use crate::stream::{Gap, SampleSubscription, StreamItem};
+use crate::usb::{self, Request, USBDevices};
+use futures::channel::mpsc;
+use futures::lock::Mutex;
+use futures::prelude::*;
+use percent_encoding::percent_decode_str;
+use futures::stream::FuturesUnordered;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError};
+use warp::http::StatusCode;
+use warp::reply::{Json, WithStatus};
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Filter, Rejection, Reply};
+
+// Devices acquired through the API, keyed by device id
+type Sessions = Arc<Mutex<HashMap<String, Session>>>;
+
+type Channels = (mpsc::Sender<Request>, mpsc::Receiver<Notification>);
+
+enum Session {
+    // Acquired through the REST API. The channels are kept here so that the device loop stays
+    // alive until the device is released.
+    Rest(Channels),
+    // Held by a websocket or a calibration check, which read the notifications themselves. The
+    // requests tell its device loop apart from one started after the device was acquired again.
+    Held(mpsc::Sender<Request>),
+}
+
+impl Session {
+    fn requests(&self) -> &mpsc::Sender<Request> {
+        match self {
+            Session::Rest((in_tx, _)) => in_tx,
+            Session::Held(in_tx) => in_tx,
+        }
+    }
+}
+
+fn held_error(id: &str) -> WithStatus<Json> {
+    json_error(StatusCode::CONFLICT, format!("{} is held by a websocket or a calibration check", id))
+}
+
+type Profiles = Arc<Vec<Profile>>;
+
+// Recordings are downloaded to `dir`. A second download of a recording that is running is refused,
+// both would append to the same part file.
+struct Downloads {
+    dir: PathBuf,
+    running: std::sync::Mutex<HashSet<PathBuf>>,
+}
+
+impl Downloads {
+    // File a recording of a device is downloaded to, the same every time so that a download
+    // resumes the part file left by an earlier one
+    fn path(&self, id: &str, recording_id: u16) -> PathBuf {
+        let id: String = id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        self.dir.join(format!("{}-{}.rec", id, recording_id))
+    }
+
+    // Marks the download to `path` as running until the returned guard is dropped
+    fn start(self: &Arc<Self>, path: &Path) -> Option<RunningDownload> {
+        let mut running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        if !running.insert(path.to_path_buf()) {
+            return None;
+        }
+        Some(RunningDownload {
+            downloads: Arc::clone(self),
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+struct RunningDownload {
+    downloads: Arc<Downloads>,
+    path: PathBuf,
+}
+
+impl Drop for RunningDownload {
+    fn drop(&mut self) {
+        let mut running = self.downloads.running.lock().unwrap_or_else(PoisonError::into_inner);
+        running.remove(&self.path);
+    }
+}
+
+// A command sent over the websocket. The optional tag is copied to the reply so that a client
+// with several commands outstanding can tell the replies apart.
+#[derive(Deserialize)]
+struct WsCommand {
+    #[serde(default)]
+    tag: Option<u64>,
+    #[serde(flatten)]
+    command: Command,
+}
+
+// A reply sent over the websocket
+#[derive(Serialize)]
+struct WsReply {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<u64>,
+    #[serde(flatten)]
+    response: Option<Response>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl WsReply {
+    fn new(tag: Option<u64>, reply: usb::Reply) -> Self {
+        match reply {
+            Ok(response) => WsReply {
+                tag,
+                response: Some(response),
+                error: None,
+            },
+            Err(e) => WsReply {
+                tag,
+                response: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+// Devices to apply a profile to
+#[derive(Deserialize)]
+struct ApplyBody {
+    devices: Vec<String>,
+}
+
+// Outcome of applying a profile to one device
+#[derive(Serialize)]
+struct Applied {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changes: Option<Vec<ConfigDiff>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Query of the sample stream: the longest gap to conceal, in packets, up to
+// `stream::MAX_CONCEAL_PACKETS`
+#[derive(Deserialize)]
+struct SamplesQuery {
+    #[serde(default)]
+    conceal: u16,
+}
+
+// Filters for the sample stream, sent over its websocket. An empty list stops filtering.
+#[derive(Deserialize, Serialize)]
+struct FilterSelection {
+    filters: Vec<FilterSpec>,
+}
+
+// A block or a gap of the sample stream
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum WsStreamItem<'a> {
+    Block(WsBlock<'a>),
+    Gap(&'a Gap),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WsBlock<'a> {
+    counter: u16,
+    flags: u8,
+    unix_millis: u64,
+    channels: usize,
+    unit: SampleUnit,
+    samples: &'a [f32],
+    synthetic: &'a [bool],
+}
+
+impl<'a> From<&'a StreamItem> for WsStreamItem<'a> {
+    fn from(item: &'a StreamItem) -> Self {
+        match item {
+            StreamItem::Block(block) => WsStreamItem::Block(WsBlock::from(block)),
+            StreamItem::Gap(gap) => WsStreamItem::Gap(gap),
+        }
+    }
+}
+
+impl<'a> From<&'a SampleBlock> for WsBlock<'a> {
+    fn from(block: &'a SampleBlock) -> Self {
+        WsBlock {
+            counter: block.counter,
+            flags: block.flags.0,
+            unix_millis: clock::unix_millis(block.timestamp),
+            channels: block.channels,
+            unit: block.unit,
+            samples: &block.samples,
+            synthetic: &block.synthetic,
+        }
+    }
+}
+
+// Sent over the websocket of a download. An error ends it like completion does.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum WsDownloadEvent<'a> {
+    Progress(DownloadProgress),
+    Complete { path: &'a Path },
+}
+
+// Offset of the device clock as measured now
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ClockStatus {
+    offset_millis: i64,
+    round_trip_millis: u64,
+    drift_ppm: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+fn json_error(status: StatusCode, error: impl Into<String>) -> WithStatus<Json> {
+    let body = ErrorBody {
+        error: error.into(),
+    };
+    warp::reply::with_status(warp::reply::json(&body), status)
+}
+
+fn client_error(e: ClientError) -> WithStatus<Json> {
+    let status = match e {
+        ClientError::NotFound(_) => StatusCode::NOT_FOUND,
+        ClientError::InvalidConfig(_) => StatusCode::BAD_REQUEST,
+        ClientError::Request(usb::RequestError::Timeout(_)) => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+    json_error(status, e.to_string())
+}
+
+fn calibration_error(e: CalibrationError) -> WithStatus<Json> {
+    let status = match e {
+        CalibrationError::Client(e) => return client_error(e),
+        CalibrationError::NotFound(_) => StatusCode::NOT_FOUND,
+        CalibrationError::NoSerial(_) => StatusCode::CONFLICT,
+        CalibrationError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        CalibrationError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+    json_error(status, e.to_string())
+}
+
+fn json_ok<T: Serialize>(value: &T) -> WithStatus<Json> {
+    warp::reply::with_status(warp::reply::json(value), StatusCode::OK)
+}
+
+/// Requests a refresh of the usb devices
+pub fn notify(notify_tx: &mut mpsc::Sender<()>) {
+    // A full channel means that a refresh is already pending
+    if let Err(e) = notify_tx.try_send(()) {
+        if e.is_disconnected() {
+            error!("Presence detector is gone");
+        }
+    }
+}
+
+/// Creates the http server. The returned future runs until the server fails.
+pub fn create(
+    usb_devices: USBDevices,
+    notify_tx: mpsc::Sender<()>,
+    profiles: Vec<Profile>,
+    download_dir: PathBuf,
+    addr: SocketAddr,
+) -> impl Future<Output = ()> {
+    let sessions: Sessions = Default::default();
+    let downloads = Arc::new(Downloads {
+        dir: download_dir,
+        running: Default::default(),
+    });
+    let downloads = warp::any().map(move || Arc::clone(&downloads));
+    let profiles: Profiles = Arc::new(profiles);
+    let profiles = warp::any().map(move || Arc::clone(&profiles));
+    let usb_devices = warp::any().map(move || usb_devices.clone());
+    let notify_tx = warp::any().map(move || notify_tx.clone());
+    let sessions = warp::any().map(move || Arc::clone(&sessions));
+
+    let list = warp::path!("api" / "devices")
+        .and(warp::get())
+        .and(usb_devices.clone())
+        .and_then(list_devices);
+    let rescan = warp::path!("api" / "devices" / "rescan")
+        .and(warp::post())
+        .and(notify_tx)
+        .and_then(rescan);
+    let acquire = warp::path!("api" / "devices" / String / "acquire")
+        .and(warp::post())
+        .and(usb_devices.clone())
+        .and(sessions.clone())
+        .and_then(acquire);
+    let release = warp::path!("api" / "devices" / String / "release")
+        .and(warp::post())
+        .and(usb_devices.clone())
+        .and(sessions.clone())
+        .and_then(release);
+    let ws = warp::path!("api" / "devices" / String / "ws")
+        .and(warp::ws())
+        .and(usb_devices.clone())
+        .and(sessions.clone())
+        .and_then(connect);
+    let samples = warp::path!("api" / "devices" / String / "samples")
+        .and(warp::ws())
+        .and(warp::query::<SamplesQuery>())
+        .and(usb_devices.clone())
+        .and(sessions.clone())
+        .and_then(samples);
+    let stream_stats = warp::path!("api" / "devices" / String / "stream")
+        .and(warp::get())
+        .and(usb_devices.clone())
+        .and_then(stream_stats);
+    let get_calibration = warp::path!("api" / "devices" / String / "calibration")
+        .and(warp::get())
+        .and(usb_devices.clone())
+        .and_then(get_calibration);
+    let check_calibration = warp::path!("api" / "devices" / String / "calibration" / "check")
+        .and(warp::post())
+        .and(usb_devices.clone())
+        .and(sessions.clone())
+        .and_then(check_calibration);
+    let get_clock = warp::path!("api" / "devices" / String / "clock")
+        .and(warp::get())
+        .and(usb_devices.clone())
+        .and(sessions.clone())
+        .and_then(get_clock);
+    let sync_clock = warp::path!("api" / "devices" / String / "clock" / "sync")
+        .and(warp::post())
+        .and(usb_devices.clone())
+        .and(sessions.clone())
+        .and_then(sync_clock);
+    let recordings = warp::path!("api" / "devices" / String / "recordings")
+        .and(warp::get())
+        .and(usb_devices.clone())
+        .and(sessions.clone())
+        .and_then(list_recordings);
+    let download = warp::path!("api" / "devices" / String / "recordings" / u16 / "download")
+        .and(warp::ws())
+        .and(usb_devices.clone())
+        .and(sessions.clone())
+        .and(downloads)
+        .and_then(download);
+    let get_config = warp::path!("api" / "devices" / String / "config")
+        .and(warp::get())
+        .and(usb_devices.clone())
+        .and(sessions.clone())
+        .and_then(get_config);
+    let put_config = warp::path!("api" / "devices" / String / "config")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(usb_devices.clone())
+        .and(sessions.clone())
+        .and_then(put_config);
+    let diff = warp::path!("api" / "devices" / String / "config" / "diff" / String)
+        .and(warp::get())
+        .and(usb_devices.clone())
+        .and(sessions.clone())
+        .and(profiles.clone())
+        .and_then(diff_profile);
+    let list_profiles = warp::path!("api" / "profiles")
+        .and(warp::get())
+        .and(profiles.clone())
+        .and_then(list_profiles);
+    let apply = warp::path!("api" / "profiles" / String / "apply")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(usb_devices)
+        .and(sessions)
+        .and(profiles)
+        .and_then(apply_profile);
+
+    let routes = list
+        .or(rescan)
+        .or(acquire)
+        .or(release)
+        .or(ws)
+        .or(samples)
+        .or(stream_stats)
+        .or(get_calibration)
+        .or(check_calibration)
+        .or(get_clock)
+        .or(sync_clock)
+        .or(recordings)
+        .or(download)
+        .or(get_config)
+        .or(put_config)
+        .or(diff)
+        .or(list_profiles)
+        .or(apply)
+        .recover(handle_rejection);
+    warp::serve(routes).run(addr)
+}
+
+// Device ids come from serial numbers and may contain characters that are escaped in the path
+fn decode_id(id: &str) -> String {
+    percent_decode_str(id).decode_utf8_lossy().into_owned()
+}
+
+async fn list_devices(usb_devices: USBDevices) -> Result<Json, Infallible> {
+    Ok(warp::reply::json(&usb_devices.devices().await))
+}
+
+async fn rescan(mut notify_tx: mpsc::Sender<()>) -> Result<WithStatus<Json>, Infallible> {
+    notify(&mut notify_tx);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&()),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+async fn acquire(
+    id: String,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+) -> Result<WithStatus<Json>, Infallible> {
+    let id = decode_id(&id);
+    // Acquiring again would end the device loop under the websocket or the check
+    if let Some(Session::Held(_)) = sessions.lock().await.get(&id) {
+        return Ok(held_error(&id));
+    }
+    let channels = match usb_devices.acquire_device(&id).await {
+        Ok(Some(channels)) => channels,
+        Ok(None) => {
+            return Ok(json_error(
+                StatusCode::NOT_FOUND,
+                format!("no device {} plugged in", id),
+            ))
+        }
+        Err(e) => {
+            return Ok(json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to acquire {}: {}", id, e),
+            ))
+        }
+    };
+    sessions.lock().await.insert(id.clone(), Session::Rest(channels));
+    match usb_devices.device(&id).await {
+        Some(info) => Ok(warp::reply::with_status(
+            warp::reply::json(&info),
+            StatusCode::OK,
+        )),
+        None => Ok(json_error(
+            StatusCode::NOT_FOUND,
+            format!("device {} left", id),
+        )),
+    }
+}
+
+async fn release(
+    id: String,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+) -> Result<WithStatus<Json>, Infallible> {
+    let id = decode_id(&id);
+    sessions.lock().await.remove(&id);
+    if !usb_devices.release_device(&id).await {
+        return Ok(json_error(
+            StatusCode::NOT_FOUND,
+            format!("no device {}", id),
+        ));
+    }
+    match usb_devices.device(&id).await {
+        Some(info) => Ok(warp::reply::with_status(
+            warp::reply::json(&info),
+            StatusCode::OK,
+        )),
+        // Released a device that had already left
+        None => Ok(warp::reply::with_status(
+            warp::reply::json(&()),
+            StatusCode::OK,
+        )),
+    }
+}
+
+async fn stream_stats(id: String, usb_devices: USBDevices) -> Result<WithStatus<Json>, Infallible> {
+    let id = decode_id(&id);
+    match usb_devices.stream_stats(&id).await {
+        Some(stats) => Ok(json_ok(&stats)),
+        None => Ok(json_error(StatusCode::NOT_FOUND, format!("no device {}", id))),
+    }
+}
+
+// Upgrades to a websocket streaming the samples of the device as JSON while it is acquired,
+// through the REST API or another websocket. Filters are selected with a message at any time.
+async fn samples(
+    id: String,
+    ws: Ws,
+    query: SamplesQuery,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let id = decode_id(&id);
+    let mut subscription = match usb_devices.subscribe_samples(&id).await {
+        Some(subscription) => subscription,
+        None => return Ok(Box::new(json_error(StatusCode::NOT_FOUND, format!("no device {}", id)))),
+    };
+    subscription.conceal(query.conceal);
+    Ok(Box::new(ws.on_upgrade(move |socket| async move {
+        stream_samples(socket, &id, subscription, &sessions).await;
+    })))
+}
+
+// Sends every block and gap until either side goes away. A filter selection is answered with the
+// filters in use or an error.
+async fn stream_samples(
+    socket: WebSocket,
+    id: &str,
+    mut subscription: SampleSubscription,
+    sessions: &Sessions,
+) {
+    info!("Sample stream of {} connected", id);
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    loop {
+        let msg = tokio::select! {
+            msg = ws_rx.next() => match msg {
+                Some(Ok(msg)) if msg.is_text() => {
+                    match select_filters(id, msg.as_bytes(), &mut subscription, sessions).await {
+                        Ok(filters) => serde_json::to_string(&FilterSelection { filters }),
+                        Err(error) => serde_json::to_string(&ErrorBody { error }),
+                    }
+                }
+                Some(Ok(msg)) if msg.is_close() => break,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    error!("Sample stream of {} failed: {}", id, e);
+                    break;
+                }
+                None => break,
+            },
+            item = subscription.next() => match item {
+                Some(item) => serde_json::to_string(&WsStreamItem::from(&item)),
+                None => {
+                    info!("{} left", id);
+                    let _ = ws_tx.send(Message::close()).await;
+                    break;
+                }
+            },
+        };
+        if let Err(e) = ws_tx.send(Message::text(msg.unwrap_or_default())).await {
+            error!("Sample stream of {} failed: {}", id, e);
+            break;
+        }
+    }
+    info!("Sample stream of {} closed", id);
+}
+
+// Builds the filters of a selection for the sample rate the device is set to
+async fn select_filters(
+    id: &str,
+    msg: &[u8],
+    subscription: &mut SampleSubscription,
+    sessions: &Sessions,
+) -> Result<Vec<FilterSpec>, String> {
+    let FilterSelection { filters } =
+        serde_json::from_slice(msg).map_err(|e| format!("invalid filter selection: {}", e))?;
+    if filters.is_empty() {
+        subscription.filter(None);
+        return Ok(filters);
+    }
+    let requests = match sessions.lock().await.get(id) {
+        Some(session) => session.requests().clone(),
+        None => return Err(format!("{} is not acquired", id)),
+    };
+    let config = HolterClient::from_requests(requests)
+        .config()
+        .await
+        .map_err(|e| e.to_string())?;
+    let chain = FilterChain::new(&filters, config.sample_rate_hz).map_err(|e| e.to_string())?;
+    subscription.filter(Some(chain));
+    Ok(filters)
+}
+
+async fn get_calibration(id: String, usb_devices: USBDevices) -> Result<WithStatus<Json>, Infallible> {
+    let id = decode_id(&id);
+    match usb_devices.calibration(&id).await {
+        Some(calibration) => Ok(json_ok(&calibration)),
+        None => Ok(json_error(
+            StatusCode::NOT_FOUND,
+            format!("no calibration in use for {}", id),
+        )),
+    }
+}
+
+// Runs the calibration pulse self-check, which takes a few seconds. A device nobody uses is held
+// for the check, so that it isn't acquired again halfway through.
+async fn check_calibration(
+    id: String,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+) -> Result<WithStatus<Json>, Infallible> {
+    let id = decode_id(&id);
+    let (mut client, held) = match hold_client(&id, &usb_devices, &sessions).await {
+        Ok(client) => client,
+        Err(e) => return Ok(client_error(e)),
+    };
+    let result = calibration::self_check(&usb_devices, &mut client, &id).await;
+    if let Some(in_tx) = held {
+        release_held(&id, &in_tx, &usb_devices, &sessions).await;
+    }
+    Ok(match result {
+        Ok(check) => json_ok(&check),
+        Err(e) => calibration_error(e),
+    })
+}
+
+// Measures the offset of the device clock, next to the drift over the syncs so far
+async fn get_clock(
+    id: String,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+) -> Result<WithStatus<Json>, Infallible> {
+    let id = decode_id(&id);
+    let mut client = match client_for(&id, &usb_devices, &sessions).await {
+        Ok(client) => client,
+        Err(e) => return Ok(client_error(e)),
+    };
+    let result = client.measure_clock().await;
+    let drift_ppm = client.clock_drift_ppm();
+    client.release().await;
+    Ok(match result {
+        Ok(sample) => json_ok(&ClockStatus {
+            offset_millis: sample.offset_millis,
+            round_trip_millis: sample.round_trip.as_millis() as u64,
+            drift_ppm,
+        }),
+        Err(e) => client_error(e),
+    })
+}
+
+async fn sync_clock(
+    id: String,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+) -> Result<WithStatus<Json>, Infallible> {
+    let id = decode_id(&id);
+    let mut client = match client_for(&id, &usb_devices, &sessions).await {
+        Ok(client) => client,
+        Err(e) => return Ok(client_error(e)),
+    };
+    let result = client.sync_clock().await;
+    client.release().await;
+    Ok(match result {
+        Ok(correction) => json_ok(&correction),
+        Err(e) => client_error(e),
+    })
+}
+
+async fn list_recordings(
+    id: String,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+) -> Result<WithStatus<Json>, Infallible> {
+    let id = decode_id(&id);
+    let mut client = match client_for(&id, &usb_devices, &sessions).await {
+        Ok(client) => client,
+        Err(e) => return Ok(client_error(e)),
+    };
+    let result = client.recordings().await;
+    client.release().await;
+    Ok(match result {
+        Ok(recordings) => json_ok(&recordings),
+        Err(e) => client_error(e),
+    })
+}
+
+// Upgrades to a websocket that downloads a recording to the download directory and reports the
+// progress. The device is held like by a command websocket unless it is acquired already. Closing
+// the socket cancels the download, opening it again resumes from the verified part.
+async fn download(
+    id: String,
+    recording_id: u16,
+    ws: Ws,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+    downloads: Arc<Downloads>,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let id = decode_id(&id);
+    let path = downloads.path(&id, recording_id);
+    let running = match downloads.start(&path) {
+        Some(running) => running,
+        None => {
+            return Ok(Box::new(json_error(
+                StatusCode::CONFLICT,
+                format!("recording {} of {} is being downloaded", recording_id, id),
+            )))
+        }
+    };
+    let (mut client, held) = match hold_client(&id, &usb_devices, &sessions).await {
+        Ok(client) => client,
+        Err(e) => return Ok(Box::new(client_error(e))),
+    };
+    let held = HeldDevice(held.map(|in_tx| (id.clone(), in_tx, usb_devices, sessions)));
+    let recording = match client.recordings().await {
+        Ok(recordings) => recordings.into_iter().find(|recording| recording.id == recording_id),
+        Err(e) => {
+            held.release().await;
+            return Ok(Box::new(client_error(e)));
+        }
+    };
+    let recording = match recording {
+        Some(recording) => recording,
+        None => {
+            held.release().await;
+            return Ok(Box::new(json_error(
+                StatusCode::NOT_FOUND,
+                format!("no recording {} on {}", recording_id, id),
+            )));
+        }
+    };
+    let dir = downloads.dir.clone();
+    let created = match tokio::task::spawn_blocking(move || std::fs::create_dir_all(&dir)).await {
+        Ok(result) => result,
+        Err(e) => Err(std::io::Error::other(e)),
+    };
+    if let Err(e) = created {
+        held.release().await;
+        return Ok(Box::new(json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to create {}: {}", downloads.dir.display(), e),
+        )));
+    }
+    let download = Download::new(recording, path);
+    Ok(Box::new(ws.on_upgrade(move |socket| async move {
+        run_download(socket, &id, download, client).await;
+        held.release().await;
+        drop(running);
+    })))
+}
+
+// Runs the download while forwarding its progress, then reports how it ended and closes the socket
+async fn run_download(socket: WebSocket, id: &str, mut download: Download, mut client: HolterClient) {
+    info!("Download of {} from {} connected", download.path().display(), id);
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    let mut progress = download.progress();
+    let result = {
+        let run = download.run(&mut client);
+        futures::pin_mut!(run);
+        loop {
+            let msg = tokio::select! {
+                result = &mut run => break Some(result),
+                event = progress.next() => match event {
+                    Some(event) => serde_json::to_string(&WsDownloadEvent::Progress(event)),
+                    None => continue,
+                },
+                msg = ws_rx.next() => match msg {
+                    Some(Ok(msg)) if msg.is_close() => break None,
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => {
+                        error!("Download websocket of {} failed: {}", id, e);
+                        break None;
+                    }
+                    None => break None,
+                },
+            };
+            if let Err(e) = ws_tx.send(Message::text(msg.unwrap_or_default())).await {
+                error!("Download websocket of {} failed: {}", id, e);
+                break None;
+            }
+        }
+    };
+    let result = match result {
+        Some(result) => result,
+        None => {
+            info!("Download of {} canceled, the verified part is kept", download.path().display());
+            return;
+        }
+    };
+    let mut msgs = Vec::new();
+    while let Ok(event) = progress.try_recv() {
+        msgs.push(serde_json::to_string(&WsDownloadEvent::Progress(event)));
+    }
+    msgs.push(match result {
+        Ok(()) => serde_json::to_string(&WsDownloadEvent::Complete {
+            path: download.path(),
+        }),
+        Err(e) => serde_json::to_string(&ErrorBody { error: e.to_string() }),
+    });
+    for msg in msgs {
+        if let Err(e) = ws_tx.send(Message::text(msg.unwrap_or_default())).await {
+            error!("Download websocket of {} failed: {}", id, e);
+            return;
+        }
+    }
+    let _ = ws_tx.send(Message::close()).await;
+}
+
+// Client for a device. A device acquired through the REST API or held by a websocket is shared
+// with its session, otherwise it is acquired until the client is released.
+async fn client_for(
+    id: &str,
+    usb_devices: &USBDevices,
+    sessions: &Sessions,
+) -> Result<HolterClient, ClientError> {
+    let requests = sessions.lock().await.get(id).map(|session| session.requests().clone());
+    match requests {
+        Some(requests) => Ok(shared_client(id, requests, usb_devices).await),
+        None => HolterClient::acquire(usb_devices, id).await,
+    }
+}
+
+// Client sending through the requests of a session. Clock syncs go to the history of the device.
+async fn shared_client(
+    id: &str,
+    requests: mpsc::Sender<Request>,
+    usb_devices: &USBDevices,
+) -> HolterClient {
+    let mut client = HolterClient::from_requests(requests);
+    if let Some(history) = usb_devices.clock_history(id).await {
+        client.set_clock_history(history);
+    }
+    client
+}
+
+// Like `client_for`, but a device nobody uses is held as by a websocket. The requests to pass to
+// `release_held` are returned with the client in that case.
+async fn hold_client(
+    id: &str,
+    usb_devices: &USBDevices,
+    sessions: &Sessions,
+) -> Result<(HolterClient, Option<mpsc::Sender<Request>>), ClientError> {
+    let requests = sessions.lock().await.get(id).map(|session| session.requests().clone());
+    if let Some(requests) = requests {
+        return Ok((shared_client(id, requests, usb_devices).await, None));
+    }
+    let history = usb_devices.clock_history(id).await;
+    let (in_tx, out_rx) = match usb_devices.acquire_device(id).await {
+        Ok(Some(channels)) => channels,
+        Ok(None) => return Err(ClientError::NotFound(id.to_string())),
+        Err(e) => {
+            return Err(ClientError::Acquire {
+                id: id.to_string(),
+                reason: e.to_string(),
+            })
+        }
+    };
+    sessions.lock().await.insert(id.to_string(), Session::Held(in_tx.clone()));
+    let mut client = HolterClient::new(in_tx.clone(), out_rx);
+    if let Some(history) = history {
+        client.set_clock_history(history);
+    }
+    Ok((client, Some(in_tx)))
+}
+
+fn find_profile<'a>(profiles: &'a [Profile], name: &str) -> Result<&'a Profile, WithStatus<Json>> {
+    profiles
+        .iter()
+        .find(|profile| profile.name == name)
+        .ok_or_else(|| json_error(StatusCode::NOT_FOUND, format!("no profile {}", name)))
+}
+
+async fn get_config(
+    id: String,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+) -> Result<WithStatus<Json>, Infallible> {
+    let id = decode_id(&id);
+    let mut client = match client_for(&id, &usb_devices, &sessions).await {
+        Ok(client) => client,
+        Err(e) => return Ok(client_error(e)),
+    };
+    let result = client.config().await;
+    client.release().await;
+    Ok(match result {
+        Ok(config) => json_ok(&config),
+        Err(e) => client_error(e),
+    })
+}
+
+async fn put_config(
+    id: String,
+    config: DeviceConfig,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+) -> Result<WithStatus<Json>, Infallible> {
+    let id = decode_id(&id);
+    // Checked before the device is acquired for it
+    if let Err(e) = config.validate() {
+        return Ok(client_error(e.into()));
+    }
+    let mut client = match client_for(&id, &usb_devices, &sessions).await {
+        Ok(client) => client,
+        Err(e) => return Ok(client_error(e)),
+    };
+    let result = client.set_config(&config).await;
+    client.release().await;
+    Ok(match result {
+        Ok(()) => json_ok(&config),
+        Err(e) => client_error(e),
+    })
+}
+
+async fn diff_profile(
+    id: String,
+    name: String,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+    profiles: Profiles,
+) -> Result<WithStatus<Json>, Infallible> {
+    let id = decode_id(&id);
+    let profile = match find_profile(&profiles, &decode_id(&name)) {
+        Ok(profile) => profile,
+        Err(reply) => return Ok(reply),
+    };
+    let mut client = match client_for(&id, &usb_devices, &sessions).await {
+        Ok(client) => client,
+        Err(e) => return Ok(client_error(e)),
+    };
+    let result = client.diff_profile(profile).await;
+    client.release().await;
+    Ok(match result {
+        Ok(diff) => json_ok(&diff),
+        Err(e) => client_error(e),
+    })
+}
+
+async fn list_profiles(profiles: Profiles) -> Result<Json, Infallible> {
+    Ok(warp::reply::json(&*profiles))
+}
+
+// Applies a profile to all given devices at once. Failing devices are reported next to the
+// others rather than failing the request.
+async fn apply_profile(
+    name: String,
+    body: ApplyBody,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+    profiles: Profiles,
+) -> Result<WithStatus<Json>, Infallible> {
+    let profile = match find_profile(&profiles, &decode_id(&name)) {
+        Ok(profile) => profile,
+        Err(reply) => return Ok(reply),
+    };
+    let applied = config::apply_bulk(&body.devices, profile, |id| {
+        let id = id.to_string();
+        let usb_devices = &usb_devices;
+        let sessions = &sessions;
+        async move { client_for(&id, usb_devices, sessions).await }
+    })
+    .await;
+    let applied: Vec<Applied> = applied
+        .into_iter()
+        .map(|(id, result)| match result {
+            Ok(changes) => Applied {
+                id,
+                changes: Some(changes),
+                error: None,
+            },
+            Err(e) => Applied {
+                id,
+                changes: None,
+                error: Some(e.to_string()),
+            },
+        })
+        .collect();
+    Ok(json_ok(&applied))
+}
+
+// Upgrades to a websocket bridged to the command channel of the device. A device acquired through
+// the REST API is taken over, one held by another websocket is refused, otherwise it is acquired
+// here. Either way it is held from before the upgrade and released when the socket closes, or when
+// the upgrade never happens, unless it was acquired again in the meantime.
+async fn connect(
+    id: String,
+    ws: Ws,
+    usb_devices: USBDevices,
+    sessions: Sessions,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let id = decode_id(&id);
+    let existing = {
+        let mut sessions = sessions.lock().await;
+        match sessions.remove(&id) {
+            Some(Session::Rest(channels)) => {
+                sessions.insert(id.clone(), Session::Held(channels.0.clone()));
+                Some(channels)
+            }
+            Some(held) => {
+                sessions.insert(id.clone(), held);
+                return Ok(Box::new(held_error(&id)));
+            }
+            None => None,
+        }
+    };
+    let acquired = existing.is_none();
+    let channels = match existing {
+        Some(channels) => channels,
+        None => match usb_devices.acquire_device(&id).await {
+            Ok(Some(channels)) => channels,
+            Ok(None) => {
+                return Ok(Box::new(json_error(
+                    StatusCode::NOT_FOUND,
+                    format!("no device {} plugged in", id),
+                )))
+            }
+            Err(e) => {
+                return Ok(Box::new(json_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("failed to acquire {}: {}", id, e),
+                )))
+            }
+        },
+    };
+    if acquired {
+        sessions.lock().await.insert(id.clone(), Session::Held(channels.0.clone()));
+    }
+    let held = HeldDevice(Some((id.clone(), channels.0.clone(), usb_devices, sessions)));
+    Ok(Box::new(ws.on_upgrade(move |socket| async move {
+        bridge(socket, &id, channels).await;
+        held.release().await;
+    })))
+}
+
+// A device held by a websocket. Dropped without being released, as when the upgrade fails, it is
+// released in the background.
+struct HeldDevice(Option<(String, mpsc::Sender<Request>, USBDevices, Sessions)>);
+
+impl HeldDevice {
+    async fn release(mut self) {
+        if let Some((id, in_tx, usb_devices, sessions)) = self.0.take() {
+            release_held(&id, &in_tx, &usb_devices, &sessions).await;
+        }
+    }
+}
+
+impl Drop for HeldDevice {
+    fn drop(&mut self) {
+        if let Some((id, in_tx, usb_devices, sessions)) = self.0.take() {
+            tokio::spawn(async move {
+                release_held(&id, &in_tx, &usb_devices, &sessions).await;
+            });
+        }
+    }
+}
+
+// Releases a device held through `in_tx`, unless it has been acquired again since
+async fn release_held(
+    id: &str,
+    in_tx: &mpsc::Sender<Request>,
+    usb_devices: &USBDevices,
+    sessions: &Sessions,
+) {
+    let mut sessions = sessions.lock().await;
+    match sessions.get(id) {
+        Some(Session::Held(held)) if held.same_receiver(in_tx) => {
+            sessions.remove(id);
+            usb_devices.release_device(id).await;
+        }
+        _ => info!("{} was released or acquired again meanwhile", id),
+    }
+}
+
+// Forwards commands to the device and every response and notification back until either side
+// goes away. Commands come as JSON in text frames or as raw commands in binary frames, everything
+// sent back is JSON in text frames. Commands are passed on without waiting for earlier ones to be
+// answered.
+async fn bridge(socket: WebSocket, id: &str, (mut in_tx, mut out_rx): Channels) {
+    info!("Websocket connected to {}", id);
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    let mut replies = FuturesUnordered::new();
+    loop {
+        let reply = tokio::select! {
+            msg = ws_rx.next() => match msg {
+                Some(Ok(msg)) if msg.is_text() || msg.is_binary() => {
+                    let parsed = if msg.is_text() {
+                        serde_json::from_slice(msg.as_bytes()).map_err(|e| e.to_string())
+                    } else {
+                        raw_command(msg.as_bytes())
+                    };
+                    let WsCommand { tag, command } = match parsed {
+                        Ok(cmd) => cmd,
+                        Err(e) => {
+                            let body = ErrorBody {
+                                error: format!("invalid command: {}", e),
+                            };
+                            let body = serde_json::to_string(&body).unwrap_or_default();
+                            if let Err(e) = ws_tx.send(Message::text(body)).await {
+                                error!("Websocket to {} failed: {}", id, e);
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    let invalid = match &command {
+                        Command::SetConfig(config) => config.validate().err(),
+                        _ => None,
+                    };
+                    if let Some(e) = invalid {
+                        serde_json::to_string(&WsReply {
+                            tag,
+                            response: None,
+                            error: Some(e.to_string()),
+                        })
+                    } else {
+                        let (req, reply_rx) = Request::new(command);
+                        if let Err(e) = in_tx.send(req).await {
+                            error!("Device {} stopped accepting messages: {}", id, e);
+                            break;
+                        }
+                        replies.push(reply_rx.map(move |reply| (tag, reply)));
+                        continue;
+                    }
+                }
+                Some(Ok(msg)) if msg.is_close() => break,
+                // Pings are answered by warp
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    error!("Websocket to {} failed: {}", id, e);
+                    break;
+                }
+                None => break,
+            },
+            reply = replies.next(), if !replies.is_empty() => match reply {
+                Some((tag, Ok(reply))) => serde_json::to_string(&WsReply::new(tag, reply)),
+                // The device loop ended without answering
+                Some((tag, Err(_))) => {
+                    serde_json::to_string(&WsReply::new(tag, Err(usb::RequestError::Disconnected)))
+                }
+                None => continue,
+            },
+            notification = out_rx.next() => match notification {
+                Some(notification) => serde_json::to_string(&notification),
+                None => {
+                    info!("Device loop of {} ended", id);
+                    let _ = ws_tx.send(Message::close()).await;
+                    break;
+                }
+            },
+        };
+        let reply = reply.unwrap_or_default();
+        if let Err(e) = ws_tx.send(Message::text(reply)).await {
+            error!("Websocket to {} failed: {}", id, e);
+            break;
+        }
+    }
+    info!("Websocket to {} closed", id);
+}
+
+// A binary frame holds a raw command, its id followed by the payload
+fn raw_command(frame: &[u8]) -> Result<WsCommand, String> {
+    match frame.split_first() {
+        Some((&id, payload)) => Ok(WsCommand {
+            tag: None,
+            command: Command::Raw {
+                id,
+                payload: payload.to_vec(),
+            },
+        }),
+        None => Err("empty binary frame".to_string()),
+    }
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<WithStatus<Json>, Infallible> {
+    if rejection.is_not_found() {
+        Ok(json_error(StatusCode::NOT_FOUND, "not found"))
+    } else if rejection
+        .find::<warp::reject::MethodNotAllowed>()
+        .is_some()
+    {
+        Ok(json_error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"))
+    } else {
+        error!("unhandled rejection: {:?}", rejection);
+        Ok(json_error(StatusCode::INTERNAL_SERVER_ERROR, "internal error"))
+    }
+}